@@ -1,17 +1,24 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::f32::consts::TAU;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 
-use ggez::conf::{WindowMode, WindowSetup};
+use ggez::audio::{self, SoundSource};
+use ggez::conf::{FullscreenType, WindowMode, WindowSetup};
 use ggez::event::{self, EventHandler};
 use ggez::glam::{IVec2, Vec2};
 use ggez::graphics::{self, Canvas, Color, DrawParam, Image, Rect};
 use ggez::input::keyboard::KeyInput;
 use ggez::mint::Point2;
 use ggez::winit::event::VirtualKeyCode;
-use ggez::{Context, ContextBuilder, GameResult};
+use ggez::{Context, ContextBuilder, GameError, GameResult};
 
 use clap::Parser;
+use serde::{Deserialize, Serialize};
 
 fn tile_rect(coords: Point2<i32>) -> Rect {
 	Rect::new(
@@ -26,6 +33,79 @@ fn lerp(progress: f32, start: f32, end: f32) -> f32 {
 	start + progress * (end - start)
 }
 
+/// Level files can name the first few channels by the color they are tinted with.
+const CHANNEL_NAMES: [&str; 6] = ["red", "blue", "green", "yellow", "purple", "orange"];
+
+/// A channel in level files is either a number or the name of its color.
+fn parse_channel(descr: &str) -> Result<u8, String> {
+	match CHANNEL_NAMES.iter().position(|&name| name == descr) {
+		Some(index) => Ok(index as u8 + 1),
+		None => descr.parse().map_err(|error| format!("{error}")),
+	}
+}
+
+fn channel_descr(channel: u8) -> String {
+	match CHANNEL_NAMES.get((channel as usize).wrapping_sub(1)) {
+		Some(name) => name.to_string(),
+		None => channel.to_string(),
+	}
+}
+
+/// Tint that tells apart the channels of objects that are linked by channel.
+/// Channel 0 is the default one and is not tinted.
+fn channel_color(channel: u8) -> Color {
+	if channel == 0 {
+		return Color::WHITE;
+	}
+	const COLORS: [Color; 6] = [
+		Color::new(1.0, 0.3, 0.3, 1.0),
+		Color::new(0.3, 0.5, 1.0, 1.0),
+		Color::new(0.3, 1.0, 0.4, 1.0),
+		Color::new(1.0, 0.9, 0.2, 1.0),
+		Color::new(0.9, 0.4, 1.0, 1.0),
+		Color::new(1.0, 0.6, 0.2, 1.0),
+	];
+	COLORS[(channel as usize - 1) % COLORS.len()]
+}
+
+/// Colors that note text can be given by name in level files,
+/// darker than the channel colors so that they can be read over the grass.
+const NOTE_COLORS: [(&str, u32); 9] = [
+	("black", 0x000000),
+	("white", 0xffffff),
+	("gray", 0x606060),
+	("red", 0xb01010),
+	("blue", 0x1030b0),
+	("green", 0x107020),
+	("yellow", 0xc0a000),
+	("purple", 0x701090),
+	("orange", 0xc05000),
+];
+
+/// A note color in level files is either the name of a color or `#rrggbb`.
+fn parse_note_color(descr: &str) -> Result<Color, String> {
+	if let Some(hex) = descr.strip_prefix('#') {
+		if hex.len() != 6 {
+			return Err(format!("\"{descr}\" is not of the form #rrggbb"));
+		}
+		return u32::from_str_radix(hex, 16)
+			.map(Color::from_rgb_u32)
+			.map_err(|error| format!("{error}"));
+	}
+	match NOTE_COLORS.iter().find(|(name, _)| *name == descr) {
+		Some((_, rgb)) => Ok(Color::from_rgb_u32(*rgb)),
+		None => Err(format!("unknown color \"{descr}\"")),
+	}
+}
+
+fn note_color_descr(color: Color) -> String {
+	let rgb = color.to_rgb_u32();
+	match NOTE_COLORS.iter().find(|(_, named_rgb)| *named_rgb == rgb) {
+		Some((name, _)) => name.to_string(),
+		None => format!("#{rgb:06x}"),
+	}
+}
+
 #[derive(Clone, Copy)]
 enum Sprite {
 	Player,
@@ -48,9 +128,79 @@ enum Sprite {
 	Key,
 	Ice,
 	Arrow,
+	Balloon,
+	Goal,
+	ConveyorRight,
+	ConveyorUp,
+	ConveyorLeft,
+	ConveyorDown,
+	Teleporter,
+	Plate,
+	OpenDoor,
+	/// Light door that can be tinted.
+	DoorTintable,
+	/// Light key that can be tinted.
+	KeyTintable,
+	Hunter,
+	Spikes,
+	CrumblingFloor,
+	CrumblingFloorCracked,
+	Pit,
+	Bomb,
+	Fire,
+	Water,
+	Magnet,
+	PlayerWalk,
+	PlayerStep,
+	GrassTufts,
+	GrassSpeckled,
+	GrassSparse,
+	Prism,
+	Glass,
 }
 
 impl Sprite {
+	/// Width and height of a sprite in the built-in spritesheet, in pixels,
+	/// other spritesheets can have bigger sprites.
+	const MIN_SIZE: u32 = 8;
+	/// Number of sprites on each side of the spritesheet, whatever the size of its sprites.
+	const SPRITESHEET_CELLS: u32 = 16;
+
+	/// Width and height of a sprite in the given spritesheet, in pixels.
+	fn size_in(spritesheet: &Image) -> f32 {
+		(spritesheet.width() / Sprite::SPRITESHEET_CELLS) as f32
+	}
+
+	/// The grass sprite of the tile at the given coords, picked by hashing the coords
+	/// so that a level looks the same every time, with plain grass being the most common.
+	fn grass_at(coords: Point2<i32>) -> Sprite {
+		const VARIANTS: [Sprite; 6] = [
+			Sprite::Grass,
+			Sprite::Grass,
+			Sprite::Grass,
+			Sprite::GrassTufts,
+			Sprite::GrassSpeckled,
+			Sprite::GrassSparse,
+		];
+		let mut hash = (coords.x as u32).wrapping_mul(0x9e37_79b1)
+			^ (coords.y as u32).wrapping_mul(0x85eb_ca77)
+			^ 0x2545_f491;
+		hash ^= hash >> 15;
+		hash = hash.wrapping_mul(0x2c1b_3c6d);
+		hash ^= hash >> 12;
+		VARIANTS[hash as usize % VARIANTS.len()]
+	}
+
+	/// Frames cycled through while the object moves, ending back on the sprite itself.
+	/// Sprites without frames stay the same.
+	fn walk_frames(self) -> &'static [Sprite] {
+		match self {
+			Sprite::Player => &[Sprite::PlayerWalk, Sprite::PlayerStep, Sprite::Player],
+			_ => &[],
+		}
+	}
+
+	/// The part of the spritesheet that is the sprite, normalized to the spritesheet size.
 	fn rect_in_spritesheet(self) -> Rect {
 		let (x, y) = match self {
 			Sprite::Player => (0, 0),
@@ -73,44 +223,194 @@ impl Sprite {
 			Sprite::Key => (7, 0),
 			Sprite::Ice => (1, 3),
 			Sprite::Arrow => (2, 4),
+			Sprite::Balloon => (6, 0),
+			Sprite::Goal => (0, 5),
+			Sprite::ConveyorRight => (1, 4),
+			Sprite::ConveyorUp => (1, 5),
+			Sprite::ConveyorLeft => (2, 5),
+			Sprite::ConveyorDown => (3, 5),
+			Sprite::Teleporter => (8, 1),
+			Sprite::Plate => (7, 1),
+			Sprite::OpenDoor => (4, 5),
+			Sprite::DoorTintable => (5, 5),
+			Sprite::KeyTintable => (6, 5),
+			Sprite::Hunter => (7, 5),
+			Sprite::Spikes => (8, 5),
+			Sprite::CrumblingFloor => (9, 5),
+			Sprite::CrumblingFloorCracked => (10, 5),
+			Sprite::Pit => (11, 5),
+			Sprite::Bomb => (12, 5),
+			Sprite::Fire => (13, 5),
+			Sprite::Water => (14, 5),
+			Sprite::Magnet => (15, 5),
+			Sprite::PlayerWalk => (0, 6),
+			Sprite::PlayerStep => (1, 6),
+			Sprite::GrassTufts => (2, 6),
+			Sprite::GrassSpeckled => (3, 6),
+			Sprite::GrassSparse => (4, 6),
+			Sprite::Prism => (5, 6),
+			Sprite::Glass => (6, 6),
 		};
-		Rect::new(
-			x as f32 * 8.0 / 128.0,
-			y as f32 * 8.0 / 128.0,
-			8.0 / 128.0,
-			8.0 / 128.0,
-		)
+		let size = 1.0 / Sprite::SPRITESHEET_CELLS as f32;
+		Rect::new(x as f32 * size, y as f32 * size, size, size)
+	}
+}
+
+/// Loads a spritesheet to use instead of the built-in one, so that sprites can be changed
+/// without recompiling. Sprites are expected where they are in the built-in one.
+fn load_spritesheet(ctx: &Context, path: &Path) -> Result<Image, String> {
+	let bytes = std::fs::read(path)
+		.map_err(|error| format!("failed to read spritesheet {}: {error}", path.display()))?;
+	let spritesheet = Image::from_bytes(ctx, &bytes)
+		.map_err(|error| format!("failed to load spritesheet {}: {error}", path.display()))?;
+	let (w, h) = (spritesheet.width(), spritesheet.height());
+	let min_size = Sprite::MIN_SIZE * Sprite::SPRITESHEET_CELLS;
+	if w != h || w % Sprite::SPRITESHEET_CELLS != 0 || w < min_size {
+		return Err(format!(
+			"spritesheet {} is {w}x{h} but should be a square of {} by {} sprites \
+			(at least {min_size}x{min_size})",
+			path.display(),
+			Sprite::SPRITESHEET_CELLS,
+			Sprite::SPRITESHEET_CELLS
+		));
 	}
+	Ok(spritesheet)
 }
 
-fn draw_sprite(
+/// Draws the sprite to fill `dst`, turned by `rotation` quarter turns counterclockwise,
+/// mirrored horizontally if `flip_x` is set.
+///
+/// The spritesheet draws a sprite as a quad of the size of its source rect in pixels
+/// (`sprite_size`, see `Sprite::size_in`), then the scale, the rotation around the offset
+/// (in these pixels too) and the destination are applied.
+fn sprite_draw_param(
 	sprite: Sprite,
+	sprite_size: f32,
 	dst: Rect,
 	z: i32,
 	color: Color,
 	rotation: f32,
-	canvas: &mut Canvas,
-	spritesheet: &Image,
-) {
-	let mut dst = dst;
-	dst.w /= 8.0;
-	dst.h /= 8.0; // Why is this needed ?
-	if rotation != 0.0 {
-		println!("warning: rotated sprites is broken for now");
-	}
-	canvas.draw(
-		spritesheet,
-		DrawParam::default()
-			.dest_rect(dst)
-			.src(sprite.rect_in_spritesheet())
-			.z(z)
-			.color(color)
-			.rotation(TAU * (rotation / 4.0)),
-	);
+	flip_x: bool,
+) -> DrawParam {
+	// Flipping mirrors around the offset, that is the center of the sprite.
+	let flip = if flip_x { -1.0 } else { 1.0 };
+	DrawParam::default()
+		.src(sprite.rect_in_spritesheet())
+		.dest(dst.center())
+		.offset(Vec2::splat(sprite_size / 2.0))
+		.scale(Vec2::new(flip * dst.w / sprite_size, dst.h / sprite_size))
+		// The y axis points down, so a positive angle turns clockwise.
+		.rotation(-TAU * (rotation / 4.0))
+		.z(z)
+		.color(color)
 }
 
-#[derive(Clone)]
+/// The ground and exits of a grid, that only change once in a while,
+/// batched into a single draw instead of a few draws per tile.
+/// On a 12x12 grid, drawing the ground took from 144 draws (one per tile) to a bit
+/// more than 288 (ground sprites on top of grass, exit arrows), it now takes 1.
+struct GroundLayer {
+	/// The `Grid::ground_generation` the layer was built from,
+	/// the layer has to be built again when it changes.
+	ground_generation: u64,
+	instances: graphics::InstanceArray,
+}
+
+impl GroundLayer {
+	fn is_up_to_date(&self, grid: &Grid) -> bool {
+		self.ground_generation == grid.ground_generation
+	}
+
+	fn new(ctx: &Context, grid: &Grid, spritesheet: &Image) -> GroundLayer {
+		let mut instances = graphics::InstanceArray::new_ordered(ctx, spritesheet.clone());
+		let sprite_size = Sprite::size_in(spritesheet);
+		for grid_y in 0..grid.h {
+			for grid_x in 0..grid.w {
+				let coords = Point2::from([grid_x, grid_y]);
+				let tile = grid.get(coords).unwrap();
+				let rect = tile_rect(coords);
+
+				let full_tile_sprite = match tile.ground {
+					Ground::Ice => Some(Sprite::Ice),
+					Ground::Crumbling { stepped: false } => Some(Sprite::CrumblingFloor),
+					Ground::Crumbling { stepped: true } => Some(Sprite::CrumblingFloorCracked),
+					Ground::Pit => Some(Sprite::Pit),
+					Ground::Water => Some(Sprite::Water),
+					_ => None,
+				};
+				if let Some(sprite) = full_tile_sprite {
+					instances.push(sprite_draw_param(
+						sprite,
+						sprite_size,
+						rect,
+						1,
+						Color::WHITE,
+						0.0,
+						false,
+					));
+				} else {
+					instances.push(sprite_draw_param(
+						Sprite::grass_at(coords),
+						sprite_size,
+						rect,
+						1,
+						Color::WHITE,
+						0.0,
+						false,
+					));
+					let sprite_and_color = match tile.ground {
+						Ground::Sapling { .. } => Some((Sprite::Sapling, Color::WHITE)),
+						Ground::Goal => Some((Sprite::Goal, Color::WHITE)),
+						Ground::Spikes => Some((Sprite::Spikes, Color::WHITE)),
+						Ground::Conveyor { direction } => {
+							let sprite = match direction {
+								IVec2 { x: 1, y: 0 } => Some(Sprite::ConveyorRight),
+								IVec2 { x: 0, y: -1 } => Some(Sprite::ConveyorUp),
+								IVec2 { x: -1, y: 0 } => Some(Sprite::ConveyorLeft),
+								IVec2 { x: 0, y: 1 } => Some(Sprite::ConveyorDown),
+								_ => None,
+							};
+							sprite.map(|sprite| (sprite, Color::WHITE))
+						},
+						Ground::Plate { channel } => Some((Sprite::Plate, channel_color(channel))),
+						Ground::OpenDoor { channel, .. } => {
+							Some((Sprite::OpenDoor, channel_color(channel)))
+						},
+						_ => None,
+					};
+					if let Some((sprite, color)) = sprite_and_color {
+						instances.push(sprite_draw_param(
+							sprite,
+							sprite_size,
+							rect,
+							2,
+							color,
+							0.0,
+							false,
+						));
+					}
+				}
+
+				for exit in tile.exits.iter() {
+					instances.push(sprite_draw_param(
+						Sprite::Arrow,
+						sprite_size,
+						rect,
+						2,
+						Color::new(0.8, 0.8, 0.8, 1.0),
+						direction_rotation(exit.direction).unwrap_or(0.0),
+						false,
+					));
+				}
+			}
+		}
+		GroundLayer { ground_generation: grid.ground_generation, instances }
+	}
+}
+
+#[derive(Clone, Default)]
 enum Animation {
+	#[default]
 	None,
 	CommingFrom {
 		src: Point2<i32>,
@@ -124,7 +424,27 @@ enum Animation {
 	},
 }
 
-#[derive(Clone, PartialEq, Eq)]
+impl Animation {
+	/// Between 0 and 1 while the object is moving to its tile.
+	fn moving_progress(&self) -> Option<f32> {
+		match self {
+			Animation::CommingFrom { time_start, duration, .. } if !self.is_finished() => {
+				Some(time_start.elapsed().as_secs_f32() / duration.as_secs_f32())
+			},
+			_ => None,
+		}
+	}
+
+	fn is_finished(&self) -> bool {
+		match self {
+			Animation::None => true,
+			Animation::CommingFrom { time_start, duration, .. }
+			| Animation::FailingToMoveTo { time_start, duration, .. } => time_start.elapsed() >= *duration,
+		}
+	}
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 enum RaygunKind {
 	/// Swap the shootee with the shooter.
 	SwapWithShooter,
@@ -134,6 +454,10 @@ enum RaygunKind {
 	TurnInto(Box<ObjKind>),
 	/// Turns the shootee *A* into a gun that turns its shootees into *A*.
 	TurnIntoTurnInto,
+	/// Removes the shootee from the grid.
+	Delete,
+	/// Pushes the shootee one tile further in the direction of the ray, if there is room.
+	Push,
 }
 
 impl RaygunKind {
@@ -143,11 +467,13 @@ impl RaygunKind {
 			RaygunKind::DuplicateShootee => Color::CYAN,
 			RaygunKind::TurnInto(_) => Color::WHITE,
 			RaygunKind::TurnIntoTurnInto => Color::new(1.0, 0.6, 0.7, 1.0),
+			RaygunKind::Delete => Color::new(0.3, 0.3, 0.3, 1.0),
+			RaygunKind::Push => Color::new(1.0, 0.5, 0.1, 1.0),
 		}
 	}
 }
 
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 enum ObjKind {
 	/// Moved by arrow keys, can shoot guns. There can be multiple players.
 	Player,
@@ -167,6 +493,10 @@ enum ObjKind {
 	MirrorSlopeUp,
 	/// Rays bounce in an intuitive way on a `\` shaped mirror.
 	MirrorSlopeDown,
+	/// Splits rays in two, going to its left and to its right (relative to the ray).
+	Prism,
+	/// Bends rays by a quarter turn to their left or to their right (relative to the ray).
+	Glass { turn: Turn },
 	/// Can not be pushed, can be cut with an axe.
 	Tree,
 	/// Cuts down trees when pushed into them.
@@ -177,10 +507,30 @@ enum ObjKind {
 	Cheese,
 	/// Moves away from the player if it has line of sight. It is shy. Bnuuy.
 	Bunny,
-	/// Like a wall but can be opened by a key.
-	Door,
-	/// Can open a door (once).
-	Key,
+	/// Moves toward the player if it has line of sight, catching it means the attempt is lost.
+	Hunter,
+	/// Lit by any ray that hits it (pushing it does nothing special), then explodes
+	/// and destroys the objects around it except walls, lighting the other bombs in range.
+	Bomb,
+	/// Pulls the closest rock in each of the four directions by one tile after each step,
+	/// if nothing is in between.
+	Magnet,
+	/// Lets objects moving in its direction through (to the tile behind it),
+	/// blocks them from the other sides. Can not be pushed.
+	OneWay { direction: IVec2 },
+	/// Spreads to the adjacent trees after each step and burns out after a few steps.
+	/// A player walking into it dies, other objects are blocked by it.
+	Fire { age: u8 },
+	/// Like a wall but can be opened by a key,
+	/// or held open by objects on the pressure plates of its channel.
+	Door { channel: u8 },
+	/// Can open a door of the same channel (once).
+	Key { channel: u8 },
+	/// So light that only a player can push it, pushing it with a chain of objects fails.
+	Balloon,
+	/// What lands on it comes out of the other teleporter of the same channel
+	/// (if there is room on the other side). Can not be pushed.
+	Teleporter { channel: u8 },
 }
 
 impl ObjKind {
@@ -200,39 +550,115 @@ impl ObjKind {
 			ObjKind::WallWithHoles => Sprite::WallWithHoles,
 			ObjKind::Cheese => Sprite::Cheese,
 			ObjKind::Bunny => Sprite::Bunny,
-			ObjKind::Door => Sprite::Door,
-			ObjKind::Key => Sprite::Key,
+			ObjKind::Hunter => Sprite::Hunter,
+			ObjKind::Bomb => Sprite::Bomb,
+			ObjKind::Fire { .. } => Sprite::Fire,
+			ObjKind::OneWay { .. } => Sprite::Arrow,
+			ObjKind::Magnet => Sprite::Magnet,
+			ObjKind::Prism => Sprite::Prism,
+			ObjKind::Glass { .. } => Sprite::Glass,
+			ObjKind::Door { channel: 0 } => Sprite::Door,
+			ObjKind::Door { .. } => Sprite::DoorTintable,
+			ObjKind::Key { channel: 0 } => Sprite::Key,
+			ObjKind::Key { .. } => Sprite::KeyTintable,
+			ObjKind::Balloon => Sprite::Balloon,
+			ObjKind::Teleporter { .. } => Sprite::Teleporter,
 		};
 		let color = match self {
 			ObjKind::Raygun(raygun_kind) => raygun_kind.color(),
+			ObjKind::OneWay { .. } => Color::new(1.0, 0.8, 0.3, 1.0),
+			ObjKind::Glass { turn: Turn::Left } => Color::new(0.7, 0.9, 1.0, 1.0),
+			ObjKind::Glass { turn: Turn::Right } => Color::new(0.8, 1.0, 0.7, 1.0),
+			ObjKind::Teleporter { channel } | ObjKind::Door { channel } | ObjKind::Key { channel } => {
+				channel_color(*channel)
+			},
 			_ => Color::WHITE,
 		};
 		(sprite, color)
 	}
+
+	/// How the object is described in level files, if it can be
+	/// (a one-way gate must face one of the four directions along the grid axes).
+	fn descr(&self) -> Option<String> {
+		let descr = match self {
+			ObjKind::Player => "player".to_string(),
+			ObjKind::Rock => "rock".to_string(),
+			ObjKind::Wall => "wall".to_string(),
+			ObjKind::Rope => "rope".to_string(),
+			ObjKind::Soap => "soap".to_string(),
+			ObjKind::Raygun(RaygunKind::SwapWithShooter) => "raygun:swap".to_string(),
+			ObjKind::Raygun(RaygunKind::DuplicateShootee) => "raygun:duplicate".to_string(),
+			ObjKind::Raygun(RaygunKind::TurnInto(into_what)) => {
+				format!("raygun:turn_into:{}", into_what.descr()?)
+			},
+			ObjKind::Raygun(RaygunKind::TurnIntoTurnInto) => "raygun:turn_into_turn_into".to_string(),
+			ObjKind::Raygun(RaygunKind::Delete) => "raygun:delete".to_string(),
+			ObjKind::Raygun(RaygunKind::Push) => "raygun:push".to_string(),
+			ObjKind::Mirror => "mirror".to_string(),
+			ObjKind::MirrorSlopeUp => "mirror_slope_up".to_string(),
+			ObjKind::MirrorSlopeDown => "mirror_slope_down".to_string(),
+			ObjKind::Tree => "tree".to_string(),
+			ObjKind::Axe => "axe".to_string(),
+			ObjKind::WallWithHoles => "wall_with_holes".to_string(),
+			ObjKind::Cheese => "cheese".to_string(),
+			ObjKind::Bunny => "bunny".to_string(),
+			ObjKind::Hunter => "hunter".to_string(),
+			ObjKind::Bomb => "bomb".to_string(),
+			ObjKind::Fire { .. } => "fire".to_string(),
+			ObjKind::Magnet => "magnet".to_string(),
+			ObjKind::Prism => "prism".to_string(),
+			ObjKind::Glass { turn } => format!("glass:{}", turn.descr()),
+			ObjKind::OneWay { direction } => format!("oneway:{}", direction_descr(*direction)?),
+			ObjKind::Door { channel: 0 } => "door".to_string(),
+			ObjKind::Door { channel } => format!("door:{}", channel_descr(*channel)),
+			ObjKind::Key { channel: 0 } => "key".to_string(),
+			ObjKind::Key { channel } => format!("key:{}", channel_descr(*channel)),
+			ObjKind::Balloon => "balloon".to_string(),
+			ObjKind::Teleporter { channel } => format!("teleporter:{}", channel_descr(*channel)),
+		};
+		Some(descr)
+	}
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 struct Obj {
 	kind: ObjKind,
+	#[serde(skip)]
 	processed: bool,
+	#[serde(skip)]
 	moved: bool,
+	#[serde(skip)]
 	animation: Animation,
+	/// Name given to the object in the level file so that notes can follow it.
+	tag: Option<String>,
 }
 
 impl Obj {
 	fn from_kind(kind: ObjKind) -> Obj {
-		Obj { kind, processed: false, moved: false, animation: Animation::None }
+		Obj {
+			kind,
+			processed: false,
+			moved: false,
+			animation: Animation::None,
+			tag: None,
+		}
 	}
 
 	fn can_move(&self) -> bool {
 		!matches!(
 			self.kind,
-			ObjKind::Wall | ObjKind::Tree | ObjKind::WallWithHoles | ObjKind::Door
+			ObjKind::Wall
+				| ObjKind::Tree
+				| ObjKind::WallWithHoles
+				| ObjKind::Door { .. }
+				| ObjKind::Teleporter { .. }
+				| ObjKind::Fire { .. }
+				| ObjKind::OneWay { .. }
 		)
 	}
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 enum Ground {
 	/// Default floor, nothing special.
 	Grass,
@@ -240,19 +666,143 @@ enum Ground {
 	Sapling { stepped_on: bool },
 	/// Stuff pushed on ice slides until it cannot coninue further or no more ice.
 	Ice,
+	/// Where objects must be delivered (depending on the level's win condition).
+	Goal,
+	/// Moves what is on it by one tile after each step, if there is room.
+	Conveyor { direction: IVec2 },
+	/// Holds open the doors of its channel while something is on it.
+	Plate { channel: u8 },
+	/// Where a door of the given channel is held open, the door closes back when it can
+	/// and the ground that was under it comes back.
+	OpenDoor { channel: u8, under: Box<Ground> },
+	/// Destroys what gets on it, a player getting on it dies.
+	Spikes,
+	/// Like grass until something gets on it, then it crumbles into a pit when that leaves.
+	Crumbling { stepped: bool },
+	/// What gets into it falls in and is gone, a player falling in dies.
+	/// A rock fills it instead, turning it into grass.
+	Pit,
+	/// Players cannot get in, other objects sink in it and are gone.
+	/// A rock fills it instead, turning it into grass.
+	Water,
 }
 
-#[derive(Clone)]
+impl Ground {
+	/// How the ground is written in level files, if it can be
+	/// (a conveyor must go in one of the four directions along the grid axes).
+	fn descr(&self) -> Option<String> {
+		match self {
+			Ground::Grass => Some("grass".to_string()),
+			Ground::Sapling { .. } => Some("sapling".to_string()),
+			Ground::Ice => Some("ice".to_string()),
+			Ground::Goal => Some("goal".to_string()),
+			Ground::Spikes => Some("spikes".to_string()),
+			Ground::Crumbling { .. } => Some("crumbling".to_string()),
+			Ground::Pit => Some("pit".to_string()),
+			Ground::Water => Some("water".to_string()),
+			Ground::Conveyor { direction } => Some(format!("conv {}", direction_descr(*direction)?)),
+			Ground::Plate { channel } => Some(format!("plate:{}", channel_descr(*channel))),
+			// Doors are only opened while playing.
+			Ground::OpenDoor { under, .. } => under.descr(),
+		}
+	}
+
+	fn can_be_entered_by(&self, kind: &ObjKind) -> bool {
+		!(matches!(self, Ground::Water) && *kind == ObjKind::Player)
+	}
+}
+
+/// How a direction is described in level files,
+/// only the four directions along the grid axes are.
+fn direction_descr(direction: IVec2) -> Option<&'static str> {
+	match direction {
+		IVec2 { x: 1, y: 0 } => Some("right"),
+		IVec2 { x: -1, y: 0 } => Some("left"),
+		IVec2 { x: 0, y: -1 } => Some("up"),
+		IVec2 { x: 0, y: 1 } => Some("down"),
+		_ => None,
+	}
+}
+
+fn direction_from_descr(descr: &str) -> Option<IVec2> {
+	match descr {
+		"right" => Some((1, 0).into()),
+		"left" => Some((-1, 0).into()),
+		"up" => Some((0, -1).into()),
+		"down" => Some((0, 1).into()),
+		_ => None,
+	}
+}
+
+/// A quarter turn, relative to the direction something is going in.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+enum Turn {
+	Left,
+	Right,
+}
+
+impl Turn {
+	/// How the turn is described in level files.
+	fn descr(self) -> &'static str {
+		match self {
+			Turn::Left => "left",
+			Turn::Right => "right",
+		}
+	}
+
+	fn from_descr(descr: &str) -> Option<Turn> {
+		match descr {
+			"left" => Some(Turn::Left),
+			"right" => Some(Turn::Right),
+			_ => None,
+		}
+	}
+
+	/// The given direction turned by a quarter turn (the y axis points down).
+	fn apply(self, direction: IVec2) -> IVec2 {
+		match self {
+			Turn::Left => IVec2::new(direction.y, -direction.x),
+			Turn::Right => IVec2::new(-direction.y, direction.x),
+		}
+	}
+}
+
+/// Number of counterclockwise quarter turns from the right direction to the given one,
+/// if it is one of the four directions along the grid axes.
+fn direction_rotation(direction: IVec2) -> Option<f32> {
+	match direction {
+		IVec2 { x: 1, y: 0 } => Some(0.0),
+		IVec2 { x: 0, y: -1 } => Some(1.0),
+		IVec2 { x: -1, y: 0 } => Some(2.0),
+		IVec2 { x: 0, y: 1 } => Some(3.0),
+		_ => None,
+	}
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 struct Exit {
 	direction: IVec2,
 	dst_level_id: String,
+	/// The named entry of the destination level the player comes in through,
+	/// the unnamed entry if none.
+	entry_name: Option<String>,
 }
 
-#[derive(Clone)]
+/// Another place than the unnamed entry where the player can come in,
+/// through the exits that name it.
+#[derive(Clone, Serialize, Deserialize)]
+struct NamedEntry {
+	name: String,
+	coords: Point2<i32>,
+	direction: IVec2,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 struct Tile {
 	obj: Option<Obj>,
 	ground: Ground,
-	exit: Option<Exit>,
+	/// At most one exit per direction, so that a tile can lead to different levels.
+	exits: Vec<Exit>,
 }
 
 impl Tile {
@@ -260,30 +810,81 @@ impl Tile {
 	const H: f32 = 80.0;
 
 	fn new() -> Tile {
-		Tile { obj: None, ground: Ground::Grass, exit: None }
+		Tile { obj: None, ground: Ground::Grass, exits: vec![] }
+	}
+
+	fn exit(&self, direction: IVec2) -> Option<&Exit> {
+		self.exits.iter().find(|exit| exit.direction == direction)
+	}
+
+	/// Adds the exit, replacing the exit in the same direction if any.
+	fn set_exit(&mut self, exit: Exit) {
+		self
+			.exits
+			.retain(|other_exit| other_exit.direction != exit.direction);
+		self.exits.push(exit);
 	}
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 struct Grid {
 	tiles: Vec<Tile>,
+	/// Width in tiles.
+	w: i32,
+	/// Height in tiles.
+	h: i32,
+	/// Toroidal grid, going out from an edge comes back from the opposite edge.
+	wrap: bool,
+	/// Changes (to a value no other grid ever had) when grounds or exits change,
+	/// so that what is drawn from them can tell when it has to be redone.
+	/// Grounds and exits set while a level is loaded need not bump it, the grid is new.
+	#[serde(skip, default = "Grid::new_ground_generation")]
+	ground_generation: u64,
 }
 
 impl Grid {
-	const W: i32 = 12;
-	const H: i32 = 12;
+	const DEFAULT_W: i32 = 12;
+	const DEFAULT_H: i32 = 12;
+	/// Bigger levels would not fit on a screen (and would take a lot of memory to make).
+	const MAX_SIZE: i32 = 64;
 
-	fn new() -> Grid {
+	fn new(w: i32, h: i32) -> Grid {
 		let mut tiles = vec![];
-		for _i in 0..(Grid::W * Grid::H) {
+		for _i in 0..(w * h) {
 			tiles.push(Tile::new());
 		}
-		Grid { tiles }
+		Grid {
+			tiles,
+			w,
+			h,
+			wrap: false,
+			ground_generation: Grid::new_ground_generation(),
+		}
+	}
+
+	fn new_ground_generation() -> u64 {
+		static NEXT_GROUND_GENERATION: AtomicU64 = AtomicU64::new(0);
+		NEXT_GROUND_GENERATION.fetch_add(1, Ordering::Relaxed)
+	}
+
+	/// To be called when grounds or exits change.
+	fn ground_changed(&mut self) {
+		self.ground_generation = Grid::new_ground_generation();
+	}
+
+	/// If the grid wraps, brings the given coords back into the grid.
+	fn wrapped(&self, coords: Point2<i32>) -> Point2<i32> {
+		if self.wrap {
+			Point2::from([coords.x.rem_euclid(self.w), coords.y.rem_euclid(self.h)])
+		} else {
+			coords
+		}
 	}
 
 	fn index(&self, coords: Point2<i32>) -> Option<usize> {
-		if 0 <= coords.x && coords.x < Grid::W && 0 <= coords.y && coords.y < Grid::H {
-			let index = (coords.y * Grid::W + coords.x) as usize;
+		let coords = self.wrapped(coords);
+		if 0 <= coords.x && coords.x < self.w && 0 <= coords.y && coords.y < self.h {
+			let index = (coords.y * self.w + coords.x) as usize;
 			assert!(index < self.tiles.len());
 			Some(index)
 		} else {
@@ -299,19 +900,118 @@ impl Grid {
 		let index = self.index(coords)?;
 		self.tiles.get_mut(index)
 	}
+
+	/// Tells if the whole line (row or column) that goes through `coords` along the given
+	/// direction is full of objects. On a wrapping grid, such a ring of objects cannot move.
+	fn is_line_full(&self, coords: Point2<i32>, direction: IVec2) -> bool {
+		let length = if direction.x != 0 { self.w } else { self.h };
+		let mut coords = IVec2::from(coords);
+		(0..length).all(|_| {
+			let full = self
+				.get(coords.into())
+				.is_some_and(|tile| tile.obj.is_some());
+			coords += direction;
+			full
+		})
+	}
+
+	/// Hash of the objects and grounds of the tiles, leaving out animations and flags.
+	/// It uses FNV-1a (no random seed and an algorithm that does not change with the Rust
+	/// version) so it is the same from one run and one build to the next.
+	fn state_hash(&self) -> u64 {
+		let mut hasher = FnvHasher::default();
+		self.w.hash(&mut hasher);
+		self.h.hash(&mut hasher);
+		for tile in self.tiles.iter() {
+			tile.ground.hash(&mut hasher);
+			tile.obj.as_ref().map(|obj| &obj.kind).hash(&mut hasher);
+		}
+		hasher.finish()
+	}
+}
+
+/// The 64-bit FNV-1a hash, unlike the standard library hasher its algorithm is fixed.
+struct FnvHasher(u64);
+
+impl Default for FnvHasher {
+	fn default() -> FnvHasher {
+		FnvHasher(0xcbf29ce484222325)
+	}
+}
+
+impl Hasher for FnvHasher {
+	fn write(&mut self, bytes: &[u8]) {
+		for byte in bytes {
+			self.0 ^= *byte as u64;
+			self.0 = self.0.wrapping_mul(0x100000001b3);
+		}
+	}
+
+	fn finish(&self) -> u64 {
+		self.0
+	}
 }
 
+#[derive(Clone)]
 enum RayAction {
-	SwapWith { with_who_coords: Point2<i32> },
+	SwapWith {
+		with_who_coords: Point2<i32>,
+	},
 	Duplicate,
-	TurnInto { into_what: ObjKind },
+	TurnInto {
+		into_what: ObjKind,
+	},
 	TurnIntoTurnInto,
+	Delete,
+	Push,
+	/// Not shot by a raygun, this is an axe flying after a push ray hit it.
+	/// It cuts the first tree in its way and lands on the tile before what stops it.
+	ThrowAxe,
+}
+
+impl RayAction {
+	/// What a ray shot by a raygun of the given kind does, `shooter_coords` being where the one
+	/// that made the raygun shoot is.
+	fn from_raygun_kind(kind: RaygunKind, shooter_coords: Point2<i32>) -> RayAction {
+		match kind {
+			RaygunKind::SwapWithShooter => RayAction::SwapWith { with_who_coords: shooter_coords },
+			RaygunKind::DuplicateShootee => RayAction::Duplicate,
+			RaygunKind::TurnInto(into_what) => RayAction::TurnInto { into_what: *into_what },
+			RaygunKind::TurnIntoTurnInto => RayAction::TurnIntoTurnInto,
+			RaygunKind::Delete => RayAction::Delete,
+			RaygunKind::Push => RayAction::Push,
+		}
+	}
 }
 
+#[derive(Clone)]
 struct Ray {
 	coords: Point2<i32>,
 	direction: IVec2,
 	action: RayAction,
+	/// Tiles travelled so far, so that rays bouncing between mirrors forever can be stopped.
+	step_count: u32,
+	/// Tiles the ray went through before getting to `coords`, for its beam to be drawn.
+	path: Vec<Point2<i32>>,
+}
+
+impl Ray {
+	const MAX_STEP_COUNT: u32 = 256;
+	/// Prisms do not split rays beyond that many rays at once,
+	/// the step count limit alone would let them multiply a lot.
+	const MAX_COUNT: usize = 64;
+	/// How long rays take to travel from a tile to the next.
+	const STEP_DURATION: Duration = Duration::from_millis(30);
+
+	/// Puts the axe carried by a `ThrowAxe` ray back on the grid, on the last free tile
+	/// it flew over. Returns where it landed, if it did.
+	fn land_axe(&self, grid: &mut Grid) -> Option<Point2<i32>> {
+		let coords = std::iter::once(self.coords)
+			.chain(self.path.iter().rev().copied())
+			.find(|coords| grid.get(*coords).is_some_and(|tile| tile.obj.is_none()))?;
+		grid.get_mut(coords).unwrap().obj = Some(Obj::from_kind(ObjKind::Axe));
+		Some(coords)
+	}
 }
 
 struct RaysAnimation {
@@ -319,7 +1019,120 @@ struct RaysAnimation {
 	duration: Duration,
 }
 
-#[derive(Clone)]
+/// The flash of a bomb exploding, over the tiles it blew.
+struct Blast {
+	center: Point2<i32>,
+	time_start: Instant,
+}
+
+impl Blast {
+	const DURATION: Duration = Duration::from_millis(300);
+}
+
+/// A speck flying away and fading, purely for show.
+struct Particle {
+	position: Vec2,
+	/// In pixels per second.
+	velocity: Vec2,
+	color: Color,
+	time_start: Instant,
+}
+
+impl Particle {
+	const LIFETIME: Duration = Duration::from_millis(400);
+	/// Fraction of the velocity kept after a second.
+	const DRAG: f32 = 0.05;
+
+	/// A burst of particles flying away from the center of the given tile.
+	fn burst(coords: Point2<i32>, color: Color) -> Vec<Particle> {
+		const COUNT: usize = 10;
+		let center = Vec2::from(tile_rect(coords).center());
+		(0..COUNT)
+			.map(|index| {
+				let angle = TAU * (index as f32 + 0.5 * (index % 2) as f32) / COUNT as f32;
+				let speed = Tile::W * (2.0 + (index % 3) as f32);
+				Particle {
+					position: center,
+					velocity: Vec2::from_angle(angle) * speed,
+					color,
+					time_start: Instant::now(),
+				}
+			})
+			.collect()
+	}
+}
+
+/// The view shaking for a moment when something hits hard.
+struct Shake {
+	/// How far the view goes, in pixels, at the start of the shake.
+	intensity: f32,
+	time_start: Instant,
+}
+
+impl Shake {
+	const DURATION: Duration = Duration::from_millis(100);
+
+	/// Where the view is moved to at this point of the shake, shaking less and less.
+	fn offset(&self) -> Vec2 {
+		let time = self.time_start.elapsed().as_secs_f32();
+		let progress = (time / Shake::DURATION.as_secs_f32()).min(1.0);
+		// Incommensurable frequencies look random enough.
+		let direction = Vec2::new((time * 311.0).sin(), (time * 257.0).cos());
+		direction * self.intensity * (1.0 - progress)
+	}
+}
+
+/// Text shown for a few seconds over the level, fading in and out.
+struct Banner {
+	text: String,
+	time_start: Instant,
+	duration: Duration,
+}
+
+/// In which order the players (and the bunnies) are processed during a step. They all decide
+/// where they go before moving, the order decides which of the moves that do not depend
+/// on each other are made first (see `Simulation::player_move`).
+#[derive(Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+enum TickOrder {
+	/// Rows from top to bottom, each from left to right.
+	#[default]
+	RowMajor,
+	/// Columns from left to right, each from top to bottom.
+	ColumnMajor,
+	/// The furthest along the direction of the move go first, so that a line of players
+	/// walks as one. Ties are broken in row-major order.
+	DistanceFromInput,
+}
+
+impl TickOrder {
+	/// How the tick order is described in level files.
+	fn descr(self) -> &'static str {
+		match self {
+			TickOrder::RowMajor => "row_major",
+			TickOrder::ColumnMajor => "column_major",
+			TickOrder::DistanceFromInput => "distance_from_input",
+		}
+	}
+
+	fn from_descr(descr: &str) -> Option<TickOrder> {
+		match descr {
+			"row_major" => Some(TickOrder::RowMajor),
+			"column_major" => Some(TickOrder::ColumnMajor),
+			"distance_from_input" => Some(TickOrder::DistanceFromInput),
+			_ => None,
+		}
+	}
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+enum WinCondition {
+	/// There is no more cheese on the grid.
+	CollectAllCheese,
+	/// Every goal tile is covered by an object of the given kind.
+	CoverAllGoals { kind: ObjKind },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 struct Level {
 	grid: Grid,
 	id: String,
@@ -328,11 +1141,24 @@ struct Level {
 	notes: Vec<Note>,
 	entry_coords: Point2<i32>,
 	entry_direction: IVec2,
+	named_entries: Vec<NamedEntry>,
+	/// Kinds of objects other than players that can go through the exits of the level,
+	/// they are sent to the level the exit leads to.
+	exiting_kinds: Vec<ObjKind>,
+	/// The player shoots the rayguns on all four sides instead of only the one it faces.
+	omnidirectional: bool,
+	tick_order: TickOrder,
+	/// The player cannot move anymore once it made that many steps in the level.
+	step_limit: Option<u32>,
+	/// Levels without a win condition cannot be completed (only exited).
+	win_condition: Option<WinCondition>,
+	/// The level file the level was loaded from, if any.
+	path: Option<PathBuf>,
 }
 
 impl Level {
 	fn test() -> Level {
-		let mut grid = Grid::new();
+		let mut grid = Grid::new(Grid::DEFAULT_W, Grid::DEFAULT_H);
 		//grid.get_mut(Point2::from([3, 5])).unwrap().obj = Some(Obj::from_kind(ObjKind::Player));
 		//grid.get_mut(Point2::from([2, 5])).unwrap().obj = Some(Obj::from_kind(ObjKind::Player));
 		grid.get_mut(Point2::from([5, 4])).unwrap().obj = Some(Obj::from_kind(ObjKind::Rock));
@@ -366,20 +1192,30 @@ impl Level {
 			Some(Obj::from_kind(ObjKind::WallWithHoles));
 		grid.get_mut(Point2::from([10, 4])).unwrap().obj = Some(Obj::from_kind(ObjKind::Cheese));
 		grid.get_mut(Point2::from([10, 6])).unwrap().obj = Some(Obj::from_kind(ObjKind::Bunny));
-		grid.get_mut(Point2::from([6, 1])).unwrap().obj = Some(Obj::from_kind(ObjKind::Key));
-		grid.get_mut(Point2::from([8, 1])).unwrap().obj = Some(Obj::from_kind(ObjKind::Door));
+		grid.get_mut(Point2::from([6, 1])).unwrap().obj =
+			Some(Obj::from_kind(ObjKind::Key { channel: 0 }));
+		grid.get_mut(Point2::from([8, 1])).unwrap().obj =
+			Some(Obj::from_kind(ObjKind::Door { channel: 0 }));
 		grid.get_mut(Point2::from([7, 4])).unwrap().ground = Ground::Ice;
 		grid.get_mut(Point2::from([8, 4])).unwrap().ground = Ground::Ice;
 		grid.get_mut(Point2::from([7, 5])).unwrap().ground = Ground::Ice;
 		grid.get_mut(Point2::from([8, 5])).unwrap().ground = Ground::Ice;
-		grid.get_mut(Point2::from([11, 5])).unwrap().exit =
-			Some(Exit { direction: (1, 0).into(), dst_level_id: "test01".into() });
+		grid.get_mut(Point2::from([11, 5])).unwrap().set_exit(Exit {
+			direction: (1, 0).into(),
+			dst_level_id: "test01".into(),
+			entry_name: None,
+		});
 
 		let notes = vec![Note {
 			coords: Point2::from([2, 4]),
 			text: "uwu".to_string(),
 			scale: 20.0,
 			depth: NoteDepth::Back,
+			page: None,
+			width: None,
+			color: Color::BLACK,
+			has_box: false,
+			anchor: None,
 		}];
 
 		Level {
@@ -390,62 +1226,452 @@ impl Level {
 			notes,
 			entry_coords: [3, 5].into(),
 			entry_direction: (0, 1).into(),
+			named_entries: vec![],
+			exiting_kinds: vec![],
+			omnidirectional: true,
+			tick_order: TickOrder::RowMajor,
+			step_limit: None,
+			win_condition: None,
+			path: None,
 		}
 	}
 
-	fn load_from_text(text: &str) -> Level {
-		let mut grid = Grid::new();
-		let mut chars_to_coords: HashMap<char, Vec<Point2<i32>>> = HashMap::new();
-		let mut name = "name".to_string();
-		let mut id = None;
-		let mut error_messages = vec![];
-		let mut notes = vec![];
-		let mut lines = text.lines().enumerate();
-		let mut entry_coords = [0, 0].into();
-		let mut entry_direction = (1, 0).into();
-		while let Some((line_index, line)) = lines.next() {
-			let line_number = line_index + 1;
-			let words: Vec<_> = line.split_ascii_whitespace().collect();
-			if words.is_empty() {
-				continue;
-			}
-			match words[0] {
-				"name" => {
-					if words.len() >= 2 {
-						name = words[1..].join(" ").to_string();
-					} else {
-						error_messages.push(format!(
-							"syntax error: missing name argument at line {line_number}"
-						));
-					}
-				},
-				"id" => {
-					if words.len() >= 2 {
-						id = Some(words[1..].join(" ").to_string());
-					} else {
-						error_messages.push(format!(
-							"syntax error: missing id argument at line {line_number}"
-						));
-					}
-				},
-				"grid" => {
-					for grid_row_index in 0..Grid::H {
-						let grid_row_number = grid_row_index + 1;
-						let (_line_index, line) = if let Some(line) = lines.next() {
-							line
-						} else {
-							error_messages.push(format!(
-								"syntax error: missing {grid_row_number}-th grid row at end of file"
-							));
-							break;
-						};
-						for (x, character) in line
-							.chars()
-							.enumerate()
-							.filter_map(|(i, c)| if i % 2 == 0 { Some(c) } else { None })
-							.enumerate()
-						{
-							let coords = Point2::from([x as i32, grid_row_index]);
+	/// Where the player comes in through the entry of the given name (or the unnamed entry),
+	/// `None` if there is no such entry.
+	fn entry(&self, entry_name: Option<&str>) -> Option<(Point2<i32>, IVec2)> {
+		match entry_name {
+			None => Some((self.entry_coords, self.entry_direction)),
+			Some(entry_name) => self
+				.named_entries
+				.iter()
+				.find(|entry| entry.name == entry_name)
+				.map(|entry| (entry.coords, entry.direction)),
+		}
+	}
+
+	/// What the player is expected to do in the level, derived from what the level contains.
+	fn objective(&self) -> Option<String> {
+		let cheese_count = self
+			.grid
+			.tiles
+			.iter()
+			.filter(|tile| {
+				tile
+					.obj
+					.as_ref()
+					.is_some_and(|obj| obj.kind == ObjKind::Cheese)
+			})
+			.count();
+		let has_goals = self
+			.grid
+			.tiles
+			.iter()
+			.any(|tile| matches!(tile.ground, Ground::Goal));
+		let has_exit = self.grid.tiles.iter().any(|tile| !tile.exits.is_empty());
+		let mut objectives = vec![];
+		if cheese_count >= 1 {
+			objectives.push(format!(
+				"collect {cheese_count} cheese{}",
+				if cheese_count >= 2 { "s" } else { "" }
+			));
+		}
+		if has_goals {
+			objectives.push("cover all goals".to_string());
+		}
+		if has_exit {
+			objectives.push("reach the exit".to_string());
+		}
+		let objective = objectives.join(" and ");
+		let mut chars = objective.chars();
+		let first_char = chars.next()?;
+		Some(first_char.to_uppercase().chain(chars).collect())
+	}
+
+	/// The level id defaults to the file name (without the extension),
+	/// the error message tells which file could not be read.
+	fn load_from_file(path: &Path) -> Result<Level, String> {
+		let text = std::fs::read_to_string(path)
+			.map_err(|error| format!("level file {}: {error}", path.display()))?;
+		let file_stem = path.file_stem().unwrap_or_default().to_string_lossy();
+		let mut level = Level::load_from_text(&text, &file_stem);
+		level.path = Some(path.to_path_buf());
+		Ok(level)
+	}
+
+	/// The id set by the given level file, found without parsing the whole level
+	/// (the file is only read up to its `id` line).
+	fn id_in_file(path: &Path) -> Option<String> {
+		let file = std::fs::File::open(path).ok()?;
+		BufReader::new(file)
+			.lines()
+			.map_while(Result::ok)
+			.find_map(|line| {
+				let words: Vec<_> = line.split_ascii_whitespace().collect();
+				(words.len() >= 2 && words[0] == "id").then(|| words[1..].join(" "))
+			})
+	}
+
+	/// The `fallback_id` is used if the level does not specify its id
+	/// (the name of the level file is a good candidate).
+	fn load_from_text(text: &str, fallback_id: &str) -> Level {
+		fn parse_obj_descr(descr: &str, line_number: usize) -> Result<Option<Obj>, String> {
+			Ok(match descr {
+				"none" => None,
+				"player" => Some(Obj::from_kind(ObjKind::Player)),
+				"rock" => Some(Obj::from_kind(ObjKind::Rock)),
+				"wall" => Some(Obj::from_kind(ObjKind::Wall)),
+				"rope" => Some(Obj::from_kind(ObjKind::Rope)),
+				"soap" => Some(Obj::from_kind(ObjKind::Soap)),
+				"mirror" => Some(Obj::from_kind(ObjKind::Mirror)),
+				"mirror_slope_up" => Some(Obj::from_kind(ObjKind::MirrorSlopeUp)),
+				"mirror_slope_down" => Some(Obj::from_kind(ObjKind::MirrorSlopeDown)),
+				"tree" => Some(Obj::from_kind(ObjKind::Tree)),
+				"axe" => Some(Obj::from_kind(ObjKind::Axe)),
+				"wall_with_holes" => Some(Obj::from_kind(ObjKind::WallWithHoles)),
+				"cheese" => Some(Obj::from_kind(ObjKind::Cheese)),
+				"bunny" => Some(Obj::from_kind(ObjKind::Bunny)),
+				"hunter" => Some(Obj::from_kind(ObjKind::Hunter)),
+				"bomb" => Some(Obj::from_kind(ObjKind::Bomb)),
+				"fire" => Some(Obj::from_kind(ObjKind::Fire { age: 0 })),
+				"magnet" => Some(Obj::from_kind(ObjKind::Magnet)),
+				"prism" => Some(Obj::from_kind(ObjKind::Prism)),
+				glass if glass.starts_with("glass") => {
+					let turn = match glass.split(':').nth(1) {
+						Some(word) => match Turn::from_descr(word) {
+							Some(turn) => turn,
+							None => {
+								return Err(format!(
+									"syntax error: unknown turn \"{word}\" after \"glass\" at line {line_number}"
+								));
+							},
+						},
+						None => {
+							return Err(format!(
+								"syntax error: missing turn after \"glass\" at line {line_number}"
+							));
+						},
+					};
+					Some(Obj::from_kind(ObjKind::Glass { turn }))
+				},
+				oneway if oneway.starts_with("oneway") => {
+					let direction = match oneway.split(':').nth(1) {
+						Some(word) => match direction_from_descr(word) {
+							Some(direction) => direction,
+							None => {
+								return Err(format!(
+									"syntax error: unknown direction \"{word}\" after \"oneway\" at line {line_number}"
+								));
+							},
+						},
+						None => {
+							return Err(format!(
+								"syntax error: missing direction after \"oneway\" at line {line_number}"
+							));
+						},
+					};
+					Some(Obj::from_kind(ObjKind::OneWay { direction }))
+				},
+				"door" => Some(Obj::from_kind(ObjKind::Door { channel: 0 })),
+				door if door.starts_with("door:") => {
+					let channel = match parse_channel(&door["door:".len()..]) {
+						Ok(channel) => channel,
+						Err(error) => {
+							return Err(format!(
+								"syntax error: door channel parsing failed at line {line_number}: {error}"
+							));
+						},
+					};
+					Some(Obj::from_kind(ObjKind::Door { channel }))
+				},
+				"key" => Some(Obj::from_kind(ObjKind::Key { channel: 0 })),
+				key if key.starts_with("key:") => {
+					let channel = match parse_channel(&key["key:".len()..]) {
+						Ok(channel) => channel,
+						Err(error) => {
+							return Err(format!(
+								"syntax error: key channel parsing failed at line {line_number}: {error}"
+							));
+						},
+					};
+					Some(Obj::from_kind(ObjKind::Key { channel }))
+				},
+				"balloon" => Some(Obj::from_kind(ObjKind::Balloon)),
+				teleporter if teleporter.starts_with("teleporter") => {
+					let channel = match teleporter.split(':').nth(1).map(parse_channel) {
+						Some(Ok(channel)) => channel,
+						Some(Err(error)) => {
+							return Err(format!(
+								"syntax error: teleporter channel parsing failed at line {line_number}: {error}"
+							));
+						},
+						None => {
+							return Err(format!(
+								"syntax error: missing teleporter channel at line {line_number}"
+							));
+						},
+					};
+					Some(Obj::from_kind(ObjKind::Teleporter { channel }))
+				},
+				raygun if raygun.starts_with("raygun") => {
+					let raygun_kind = match raygun.split(':').nth(1) {
+						Some("swap") => RaygunKind::SwapWithShooter,
+						Some("duplicate") => RaygunKind::DuplicateShootee,
+						Some("turn_into_turn_into") => RaygunKind::TurnIntoTurnInto,
+						Some("delete") => RaygunKind::Delete,
+						Some("push") => RaygunKind::Push,
+						Some("turn_into") => {
+							let index = if let Some((index, _)) = raygun.match_indices(':').nth(1) {
+								index
+							} else {
+								return Err(format!(
+									"syntax error: missing object after \"turn_into\" at line {line_number}"
+								));
+							};
+							let turn_into_what = parse_obj_descr(&raygun[(index + 1)..], line_number)?;
+							let turn_into_what_kind = if let Some(obj) = turn_into_what {
+								obj.kind
+							} else {
+								return Err(format!(
+										"structural error: \"turn_into\" none is not allowed at line {line_number}"
+									));
+							};
+							RaygunKind::TurnInto(Box::new(turn_into_what_kind))
+						},
+						Some(unknown_kind) => {
+							return Err(format!(
+									"syntax error: unknown raygun kind \"{unknown_kind}\" at line {line_number}"
+								));
+						},
+						None => {
+							return Err(format!(
+								"syntax error: missing raygun model at line {line_number}"
+							));
+						},
+					};
+					Some(Obj::from_kind(ObjKind::Raygun(raygun_kind)))
+				},
+				unknown_obj => {
+					return Err(format!(
+						"syntax error: unknown object \"{unknown_obj}\" at line {line_number}"
+					));
+				},
+			})
+		}
+
+		let mut grid = Grid::new(Grid::DEFAULT_W, Grid::DEFAULT_H);
+		let mut chars_to_coords: HashMap<char, Vec<Point2<i32>>> = HashMap::new();
+		let mut name = "name".to_string();
+		let mut id: Option<(String, usize)> = None;
+		let mut error_messages = vec![];
+		let mut notes = vec![];
+		let mut lines = text.lines().enumerate();
+		let mut entry_coords = [0, 0].into();
+		let mut entry_direction = (1, 0).into();
+		let mut named_entries: Vec<NamedEntry> = vec![];
+		// Objects are tagged once they are all placed.
+		let mut tags: Vec<(Point2<i32>, String, usize)> = vec![];
+		let mut exiting_kinds = vec![];
+		let mut omnidirectional = false;
+		let mut tick_order = TickOrder::RowMajor;
+		let mut step_limit = None;
+		let mut win_condition = None;
+		// Grid rows have one glyph every `spacing` characters, with spaces in between.
+		let mut spacing = 2;
+		// Line of the first directive giving coords, a size given after it could leave them
+		// out of the grid.
+		let mut coords_line_number = None;
+		while let Some((line_index, line)) = lines.next() {
+			let line_number = line_index + 1;
+			let words: Vec<_> = line.split_ascii_whitespace().collect();
+			if words.is_empty() {
+				continue;
+			}
+			match words[0] {
+				"name" => {
+					if words.len() >= 2 {
+						name = words[1..].join(" ").to_string();
+					} else {
+						error_messages.push(format!(
+							"syntax error: missing name argument at line {line_number}"
+						));
+					}
+				},
+				"id" => {
+					if let Some((_, id_line_number)) = id {
+						// Only the first id is looked for when listing the level files.
+						error_messages.push(format!(
+							"structural error: id already given at line {id_line_number}, at line {line_number}"
+						));
+					} else if words.len() >= 2 {
+						id = Some((words[1..].join(" ").to_string(), line_number));
+					} else {
+						error_messages.push(format!(
+							"syntax error: missing id argument at line {line_number}"
+						));
+					}
+				},
+				"size" => {
+					let dimensions: Vec<i32> = match words[1..].iter().map(|word| word.parse()).collect()
+					{
+						Ok(dimensions) => dimensions,
+						Err(error) => {
+							error_messages.push(format!(
+								"syntax error: size parsing failed at line {line_number}: {error}"
+							));
+							continue;
+						},
+					};
+					let [w, h] = dimensions[..] else {
+						error_messages.push(format!(
+							"syntax error: expected a width and a height after \"size\" at line {line_number}"
+						));
+						continue;
+					};
+					if w <= 0 || h <= 0 {
+						error_messages.push(format!(
+							"structural error: size {w} {h} is empty at line {line_number}"
+						));
+						continue;
+					}
+					if w > Grid::MAX_SIZE || h > Grid::MAX_SIZE {
+						error_messages.push(format!(
+							"structural error: size {w} {h} is bigger than {max} {max} at line {line_number}",
+							max = Grid::MAX_SIZE,
+						));
+						continue;
+					}
+					if !chars_to_coords.is_empty() {
+						error_messages.push(format!(
+							"structural error: size must be given before the grid at line {line_number}"
+						));
+						continue;
+					}
+					if let Some(coords_line_number) = coords_line_number {
+						error_messages.push(format!(
+							"structural error: size must be given before the coords at line {coords_line_number}, at line {line_number}"
+						));
+						continue;
+					}
+					grid = Grid { wrap: grid.wrap, ..Grid::new(w, h) };
+				},
+				"spacing" => {
+					if !chars_to_coords.is_empty() {
+						error_messages.push(format!(
+							"structural error: spacing must be given before the grid at line {line_number}"
+						));
+						continue;
+					}
+					match words.get(1).map(|word| word.parse::<usize>()) {
+						Some(Ok(new_spacing)) if new_spacing >= 1 => spacing = new_spacing,
+						Some(Ok(_)) => error_messages.push(format!(
+							"structural error: spacing must be at least 1 at line {line_number}"
+						)),
+						Some(Err(error)) => error_messages.push(format!(
+							"syntax error: spacing parsing failed at line {line_number}: {error}"
+						)),
+						None => error_messages.push(format!(
+							"syntax error: missing spacing argument at line {line_number}"
+						)),
+					}
+				},
+				"wrap" => grid.wrap = true,
+				"omnidirectional" => omnidirectional = true,
+				"tick_order" => match words.get(1) {
+					Some(word) => match TickOrder::from_descr(word) {
+						Some(order) => tick_order = order,
+						None => error_messages.push(format!(
+							"syntax error: unknown tick order \"{word}\" at line {line_number}"
+						)),
+					},
+					None => error_messages.push(format!(
+						"syntax error: missing tick order after \"tick_order\" at line {line_number}"
+					)),
+				},
+				"exiting_objects" => {
+					for obj_descr in words[1..].iter() {
+						match parse_obj_descr(obj_descr, line_number) {
+							Ok(Some(obj)) => exiting_kinds.push(obj.kind),
+							Ok(None) => {},
+							Err(error) => error_messages.push(error),
+						}
+					}
+				},
+				"step_limit" => match words.get(1).map(|word| word.parse::<u32>()) {
+					Some(Ok(limit)) => step_limit = Some(limit),
+					Some(Err(error)) => error_messages.push(format!(
+						"syntax error: step limit parsing failed at line {line_number}: {error}"
+					)),
+					None => error_messages.push(format!(
+						"syntax error: missing step limit argument at line {line_number}"
+					)),
+				},
+				"goal" => match words.get(1) {
+					Some(&"collect_all_cheese") => win_condition = Some(WinCondition::CollectAllCheese),
+					Some(goal) if goal.starts_with("cover_goals") => {
+						let kind = match goal.split_once(':') {
+							None => ObjKind::Rock,
+							Some((_, obj_descr)) => match parse_obj_descr(obj_descr, line_number) {
+								Ok(Some(obj)) => obj.kind,
+								Ok(None) => {
+									error_messages.push(format!(
+										"structural error: \"cover_goals\" none is not allowed at line {line_number}"
+									));
+									continue;
+								},
+								Err(error) => {
+									error_messages.push(error);
+									continue;
+								},
+							},
+						};
+						win_condition = Some(WinCondition::CoverAllGoals { kind });
+					},
+					Some(unknown_goal) => error_messages.push(format!(
+						"syntax error: unknown goal \"{unknown_goal}\" at line {line_number}"
+					)),
+					None => error_messages.push(format!(
+						"syntax error: missing goal after \"goal\" at line {line_number}"
+					)),
+				},
+				"grid" => {
+					for grid_row_index in 0..grid.h {
+						let grid_row_number = grid_row_index + 1;
+						let (row_line_index, line) = if let Some(line) = lines.next() {
+							line
+						} else {
+							error_messages.push(format!(
+								"syntax error: missing {grid_row_number}-th grid row at end of file"
+							));
+							break;
+						};
+						let row_line_number = row_line_index + 1;
+						let column_count = line.chars().count().div_ceil(spacing);
+						if column_count > grid.w as usize {
+							error_messages.push(format!(
+								"structural error: grid row at line {row_line_number} \
+								has {column_count} columns but the grid is {} wide",
+								grid.w
+							));
+						}
+						if let Some((i, separator)) = line
+							.chars()
+							.enumerate()
+							.find(|&(i, c)| i % spacing != 0 && c != ' ')
+						{
+							error_messages.push(format!(
+								"syntax error: expected a space between grid columns \
+								but got '{separator}' at line {row_line_number} column {}",
+								i + 1
+							));
+						}
+						// Columns past the width of the grid were reported, they have no tile.
+						for (x, character) in line
+							.chars()
+							.step_by(spacing)
+							.take(grid.w as usize)
+							.enumerate()
+						{
+							let coords = Point2::from([x as i32, grid_row_index]);
 							let entry = chars_to_coords.entry(character);
 							entry.or_default().push(coords);
 						}
@@ -455,7 +1681,7 @@ impl Level {
 					let character = if let Some(word) = words.get(1) {
 						if *word == "space" {
 							' '
-						} else if word.len() == 1 {
+						} else if word.chars().count() == 1 {
 							word.chars().next().unwrap()
 						} else {
 							error_messages.push(format!(
@@ -469,78 +1695,17 @@ impl Level {
 						));
 						continue;
 					};
-					let obj_descr = if let Some(word) = words.get(2) {
-						word
+					// Words after the object are its parameters, `obj g oneway right`
+					// is the same as `obj g oneway:right`.
+					let obj_descr = if words.len() >= 3 {
+						words[2..].join(":")
 					} else {
 						error_messages.push(format!(
 							"syntax error: missing object description after \"obj\" at line {line_number}"
 						));
 						continue;
 					};
-					fn parse_obj_descr(descr: &str, line_number: usize) -> Result<Option<Obj>, String> {
-						Ok(match descr {
-							"none" => None,
-							"player" => Some(Obj::from_kind(ObjKind::Player)),
-							"rock" => Some(Obj::from_kind(ObjKind::Rock)),
-							"wall" => Some(Obj::from_kind(ObjKind::Wall)),
-							"rope" => Some(Obj::from_kind(ObjKind::Rope)),
-							"soap" => Some(Obj::from_kind(ObjKind::Soap)),
-							"mirror" => Some(Obj::from_kind(ObjKind::Mirror)),
-							"mirror_slope_up" => Some(Obj::from_kind(ObjKind::MirrorSlopeUp)),
-							"mirror_slope_down" => Some(Obj::from_kind(ObjKind::MirrorSlopeDown)),
-							"tree" => Some(Obj::from_kind(ObjKind::Tree)),
-							"axe" => Some(Obj::from_kind(ObjKind::Axe)),
-							"wall_with_holes" => Some(Obj::from_kind(ObjKind::WallWithHoles)),
-							"cheese" => Some(Obj::from_kind(ObjKind::Cheese)),
-							"bunny" => Some(Obj::from_kind(ObjKind::Bunny)),
-							"door" => Some(Obj::from_kind(ObjKind::Door)),
-							"key" => Some(Obj::from_kind(ObjKind::Key)),
-							raygun if raygun.starts_with("raygun") => {
-								let raygun_kind = match raygun.split(':').nth(1) {
-									Some("swap") => RaygunKind::SwapWithShooter,
-									Some("duplicate") => RaygunKind::DuplicateShootee,
-									Some("turn_into_turn_into") => RaygunKind::TurnIntoTurnInto,
-									Some("turn_into") => {
-										let index = if let Some((index, _)) = raygun.match_indices(':').nth(1)
-										{
-											index
-										} else {
-											return Err(format!(
-												"syntax error: missing object after \"turn_into\" at line {line_number}"
-											));
-										};
-										let turn_into_what =
-											parse_obj_descr(&raygun[(index + 1)..], line_number)?;
-										let turn_into_what_kind = if let Some(obj) = turn_into_what {
-											obj.kind
-										} else {
-											return Err(format!(
-												"structural error: \"turn_into\" none is not allowed at line {line_number}"
-											));
-										};
-										RaygunKind::TurnInto(Box::new(turn_into_what_kind))
-									},
-									Some(unknown_kind) => {
-										return Err(format!(
-											"syntax error: unknown raygun kind \"{unknown_kind}\" at line {line_number}"
-										));
-									},
-									None => {
-										return Err(format!(
-											"syntax error: missing raygun model at line {line_number}"
-										));
-									},
-								};
-								Some(Obj::from_kind(ObjKind::Raygun(raygun_kind)))
-							},
-							unknown_obj => {
-								return Err(format!(
-									"syntax error: unknown object \"{unknown_obj}\" at line {line_number}"
-								));
-							},
-						})
-					}
-					let obj = match parse_obj_descr(obj_descr, line_number) {
+					let obj = match parse_obj_descr(&obj_descr, line_number) {
 						Ok(obj) => obj,
 						Err(error) => {
 							error_messages.push(error);
@@ -557,7 +1722,7 @@ impl Level {
 					let character = if let Some(word) = words.get(1) {
 						if *word == "space" {
 							' '
-						} else if word.len() == 1 {
+						} else if word.chars().count() == 1 {
 							word.chars().next().unwrap()
 						} else {
 							error_messages.push(format!(
@@ -583,6 +1748,43 @@ impl Level {
 						"grass" => Ground::Grass,
 						"sapling" => Ground::Sapling { stepped_on: false },
 						"ice" => Ground::Ice,
+						"goal" => Ground::Goal,
+						"spikes" => Ground::Spikes,
+						"crumbling" => Ground::Crumbling { stepped: false },
+						"pit" => Ground::Pit,
+						"water" => Ground::Water,
+						"conv" => {
+							let direction = match words.get(3) {
+								Some(&"right") => (1, 0).into(),
+								Some(&"left") => (-1, 0).into(),
+								Some(&"up") => (0, -1).into(),
+								Some(&"down") => (0, 1).into(),
+								Some(unknown_direction) => {
+									error_messages.push(format!(
+										"syntax error: unkonwn direction \"{unknown_direction}\" after \"conv\" at line {line_number}"
+									));
+									continue;
+								},
+								None => {
+									error_messages.push(format!(
+										"syntax error: missing direction after \"conv\" at line {line_number}"
+									));
+									continue;
+								},
+							};
+							Ground::Conveyor { direction }
+						},
+						plate if plate.starts_with("plate:") => {
+							match parse_channel(&plate["plate:".len()..]) {
+								Ok(channel) => Ground::Plate { channel },
+								Err(error) => {
+									error_messages.push(format!(
+										"syntax error: plate channel parsing failed at line {line_number}: {error}"
+									));
+									continue;
+								},
+							}
+						},
 						unknown_obj => {
 							error_messages.push(format!(
 								"syntax error: unknown object \"{unknown_obj}\" at line {line_number}"
@@ -600,7 +1802,7 @@ impl Level {
 					let character = if let Some(word) = words.get(1) {
 						if *word == "space" {
 							' '
-						} else if word.len() == 1 {
+						} else if word.chars().count() == 1 {
 							word.chars().next().unwrap()
 						} else {
 							error_messages.push(format!(
@@ -639,66 +1841,23 @@ impl Level {
 							));
 							continue;
 						};
-						entry_coords = coords;
-						entry_direction = direction;
-					} else {
-						error_messages.push("structural error: no entry".to_string());
-						continue;
-					}
-				},
-				"exit" => {
-					let character = if let Some(word) = words.get(1) {
-						if *word == "space" {
-							' '
-						} else if word.len() == 1 {
-							word.chars().next().unwrap()
-						} else {
-							error_messages.push(format!(
-								"syntax error: should be a single character after \"exit\" at line {line_number}"
-							));
-							continue;
-						}
-					} else {
-						error_messages.push(format!(
-							"syntax error: missing character after \"exit\" at line {line_number}"
-						));
-						continue;
-					};
-					let direction = if let Some(word) = words.get(2) {
-						match *word {
-							"right" => (1, 0).into(),
-							"left" => (-1, 0).into(),
-							"up" => (0, -1).into(),
-							"down" => (0, 1).into(),
-							unknown_direction => {
-								error_messages.push(format!(
-									"syntax error: unkonwn direction \"{unknown_direction}\" after \"exit\" at line {line_number}"
-								));
-								continue;
+						// An entry with a name (the word after its direction) is a named entry,
+						// otherwise it is the unnamed entry.
+						match words.get(3) {
+							Some(name) => {
+								named_entries.push(NamedEntry { name: name.to_string(), coords, direction })
+							},
+							None => {
+								entry_coords = coords;
+								entry_direction = direction;
 							},
 						}
 					} else {
-						error_messages.push(format!(
-							"syntax error: missing direction after \"exit\" at line {line_number}"
-						));
-						continue;
-					};
-					let dst_level_id = if let Some(word) = words.get(3) {
-						word.to_string()
-					} else {
-						error_messages.push(format!(
-							"syntax error: missing destination level id after \"exit\" at line {line_number}"
-						));
+						error_messages.push("structural error: no entry".to_string());
 						continue;
-					};
-					if let Some(coords_list) = chars_to_coords.get(&character) {
-						for coords in coords_list {
-							grid.get_mut(*coords).unwrap().exit =
-								Some(Exit { direction, dst_level_id: dst_level_id.clone() })
-						}
 					}
 				},
-				"note" => {
+				"entry_at" => {
 					let x: i32 = if let Some(word) = words.get(1) {
 						match word.parse() {
 							Ok(value) => value,
@@ -731,14 +1890,168 @@ impl Level {
 						));
 						continue;
 					};
-					let coords = Point2::from([x, y]);
-					let scale: f32 = if let Some(word) = words.get(3) {
-						match word.parse() {
-							Ok(value) => value,
-							Err(error) => {
-								error_messages.push(format!(
-									"syntax error: scale parsing failed at line {line_number}: {error}"
-								));
+					if !(0..grid.w).contains(&x) || !(0..grid.h).contains(&y) {
+						error_messages.push(format!(
+							"structural error: entry at ({x}, {y}) is out of the grid at line {line_number}"
+						));
+						continue;
+					}
+					let direction = if let Some(word) = words.get(3) {
+						match *word {
+							"right" => (1, 0).into(),
+							"left" => (-1, 0).into(),
+							"up" => (0, -1).into(),
+							"down" => (0, 1).into(),
+							unknown_direction => {
+								error_messages.push(format!(
+									"syntax error: unkonwn direction \"{unknown_direction}\" after \"entry_at\" at line {line_number}"
+								));
+								continue;
+							},
+						}
+					} else {
+						error_messages.push(format!(
+							"syntax error: missing direction after \"entry_at\" at line {line_number}"
+						));
+						continue;
+					};
+					let coords = Point2::from([x, y]);
+					coords_line_number.get_or_insert(line_number);
+					match words.get(4) {
+						Some(name) => {
+							named_entries.push(NamedEntry { name: name.to_string(), coords, direction })
+						},
+						None => {
+							entry_coords = coords;
+							entry_direction = direction;
+						},
+					}
+				},
+				"exit" => {
+					let character = if let Some(word) = words.get(1) {
+						if *word == "space" {
+							' '
+						} else if word.chars().count() == 1 {
+							word.chars().next().unwrap()
+						} else {
+							error_messages.push(format!(
+								"syntax error: should be a single character after \"exit\" at line {line_number}"
+							));
+							continue;
+						}
+					} else {
+						error_messages.push(format!(
+							"syntax error: missing character after \"exit\" at line {line_number}"
+						));
+						continue;
+					};
+					let direction = if let Some(word) = words.get(2) {
+						match *word {
+							"right" => (1, 0).into(),
+							"left" => (-1, 0).into(),
+							"up" => (0, -1).into(),
+							"down" => (0, 1).into(),
+							unknown_direction => {
+								error_messages.push(format!(
+									"syntax error: unkonwn direction \"{unknown_direction}\" after \"exit\" at line {line_number}"
+								));
+								continue;
+							},
+						}
+					} else {
+						error_messages.push(format!(
+							"syntax error: missing direction after \"exit\" at line {line_number}"
+						));
+						continue;
+					};
+					let dst_level_id = if let Some(word) = words.get(3) {
+						word.to_string()
+					} else {
+						error_messages.push(format!(
+							"syntax error: missing destination level id after \"exit\" at line {line_number}"
+						));
+						continue;
+					};
+					let entry_name = words.get(4).map(|word| word.to_string());
+					if let Some(coords_list) = chars_to_coords.get(&character) {
+						for coords in coords_list {
+							grid.get_mut(*coords).unwrap().set_exit(Exit {
+								direction,
+								dst_level_id: dst_level_id.clone(),
+								entry_name: entry_name.clone(),
+							});
+						}
+					}
+				},
+				"tag" => {
+					let coords = match (
+						words.get(1).map(|word| word.parse()),
+						words.get(2).map(|word| word.parse()),
+					) {
+						(Some(Ok(x)), Some(Ok(y))) => Point2::from([x, y]),
+						(Some(Err(error)), _) | (_, Some(Err(error))) => {
+							error_messages.push(format!(
+								"syntax error: coordinate parsing failed at line {line_number}: {error}"
+							));
+							continue;
+						},
+						_ => {
+							error_messages.push(format!(
+								"syntax error: missing coordinates after \"tag\" at line {line_number}"
+							));
+							continue;
+						},
+					};
+					let Some(name) = words.get(3) else {
+						error_messages.push(format!(
+							"syntax error: missing name after \"tag\" at line {line_number}"
+						));
+						continue;
+					};
+					coords_line_number.get_or_insert(line_number);
+					tags.push((coords, name.to_string(), line_number));
+				},
+				"note" => {
+					let x: i32 = if let Some(word) = words.get(1) {
+						match word.parse() {
+							Ok(value) => value,
+							Err(error) => {
+								error_messages.push(format!(
+									"syntax error: x coordinate parsing failed at line {line_number}: {error}"
+								));
+								continue;
+							},
+						}
+					} else {
+						error_messages.push(format!(
+							"syntax error: missing x coordinate at line {line_number}"
+						));
+						continue;
+					};
+					let y: i32 = if let Some(word) = words.get(2) {
+						match word.parse() {
+							Ok(value) => value,
+							Err(error) => {
+								error_messages.push(format!(
+									"syntax error: y coordinate parsing failed at line {line_number}: {error}"
+								));
+								continue;
+							},
+						}
+					} else {
+						error_messages.push(format!(
+							"syntax error: missing y coordinate at line {line_number}"
+						));
+						continue;
+					};
+					let coords = Point2::from([x, y]);
+					let scale: f32 = if let Some(word) = words.get(3) {
+						match word.parse() {
+							Ok(value) => value,
+							Err(error) => {
+								error_messages.push(format!(
+									"syntax error: scale parsing failed at line {line_number}: {error}"
+								));
 								continue;
 							},
 						}
@@ -762,15 +2075,165 @@ impl Level {
 							continue;
 						},
 					};
-					let text = words[5..].join(" ").replace(';', "\n").replace("\n\n", ";");
-					notes.push(Note { coords, text, scale, depth })
+					// Optional settings come before the text and end with a `|` (so that the text
+					// can start with any word), a keyword followed by a value (except for `box`
+					// that is just a keyword). Without a `|` all the words are the text.
+					let (option_words, text_words) =
+						match words[5..].iter().position(|word| *word == "|") {
+							Some(index) => (&words[5..(5 + index)], &words[(5 + index + 1)..]),
+							None => (&words[5..5], &words[5..]),
+						};
+					let mut page = None;
+					let mut width = None;
+					let mut color = Color::BLACK;
+					let mut has_box = false;
+					let mut anchor = None;
+					let mut option_error = None;
+					let mut option_words = option_words.iter();
+					while let (None, Some(option)) = (&option_error, option_words.next()) {
+						let value = if *option == "box" {
+							None
+						} else {
+							option_words.next()
+						};
+						match *option {
+							"page" => match value.map(|word| word.parse()) {
+								Some(Ok(value)) => page = Some(value),
+								Some(Err(error)) => {
+									option_error = Some(format!(
+										"syntax error: page parsing failed at line {line_number}: {error}"
+									))
+								},
+								None => {
+									option_error = Some(format!(
+										"syntax error: missing page number at line {line_number}"
+									))
+								},
+							},
+							"width" => match value.map(|word| word.parse()) {
+								Some(Ok(value)) => width = Some(value),
+								Some(Err(error)) => {
+									option_error = Some(format!(
+										"syntax error: width parsing failed at line {line_number}: {error}"
+									))
+								},
+								None => {
+									option_error =
+										Some(format!("syntax error: missing width at line {line_number}"))
+								},
+							},
+							"color" => match value.map(|word| parse_note_color(word)) {
+								Some(Ok(value)) => color = value,
+								Some(Err(error)) => {
+									option_error = Some(format!(
+										"syntax error: color parsing failed at line {line_number}: {error}"
+									))
+								},
+								None => {
+									option_error =
+										Some(format!("syntax error: missing color at line {line_number}"))
+								},
+							},
+							"on" => match value {
+								Some(tag) => anchor = Some(tag.to_string()),
+								None => {
+									option_error = Some(format!(
+										"syntax error: missing tag after \"on\" at line {line_number}"
+									))
+								},
+							},
+							"box" => has_box = true,
+							unknown_option => {
+								option_error = Some(format!(
+									"syntax error: unknown note option \"{unknown_option}\" at line {line_number}"
+								))
+							},
+						}
+					}
+					if let Some(error) = option_error {
+						error_messages.push(error);
+						continue;
+					}
+					let text = text_words.join(" ").replace(';', "\n").replace("\n\n", ";");
+					coords_line_number.get_or_insert(line_number);
+					notes.push(Note { coords, text, scale, depth, page, width, color, has_box, anchor })
 				},
+				// Comments. Grid rows are consumed by the grid directive,
+				// so a `#` in the grid art is not mistaken for a comment.
+				comment if comment.starts_with('#') => {},
 				unknown_word => error_messages.push(format!(
 					"syntax error: unknown \"{unknown_word}\" at line {line_number}"
 				)),
 			}
 		}
-		let id = id.expect("msising id in level file");
+		for (coords, name, line_number) in tags {
+			match grid.get_mut(coords).and_then(|tile| tile.obj.as_mut()) {
+				Some(obj) => obj.tag = Some(name),
+				None => error_messages.push(format!(
+					"structural error: no object to tag at ({}, {}) at line {line_number}",
+					coords.x, coords.y
+				)),
+			}
+		}
+		for note in notes.iter() {
+			if let Some(anchor) = &note.anchor {
+				let is_tag = grid.tiles.iter().any(|tile| {
+					tile
+						.obj
+						.as_ref()
+						.is_some_and(|obj| obj.tag.as_ref() == Some(anchor))
+				});
+				if !is_tag {
+					error_messages.push(format!(
+						"structural error: a note is on \"{anchor}\" but no object has that tag"
+					));
+				}
+			}
+		}
+		let mut entry_names = HashSet::new();
+		for entry in named_entries.iter() {
+			if !entry_names.insert(&entry.name) {
+				error_messages.push(format!(
+					"structural error: there are several entries named \"{}\"",
+					entry.name
+				));
+			}
+		}
+		// Nothing can stay on spikes or over a pit or water, so nothing can start there either.
+		for grid_y in 0..grid.h {
+			for grid_x in 0..grid.w {
+				let coords = Point2::from([grid_x, grid_y]);
+				let tile = grid.get(coords).unwrap();
+				let ground_descr = match tile.ground {
+					Ground::Spikes => "on spikes",
+					Ground::Pit => "over a pit",
+					Ground::Water => "on water",
+					_ => continue,
+				};
+				let is_entry =
+					coords == entry_coords || named_entries.iter().any(|entry| entry.coords == coords);
+				if tile.obj.is_some() || is_entry {
+					error_messages.push(format!(
+						"structural error: {} {ground_descr} at ({grid_x}, {grid_y})",
+						if is_entry { "entry" } else { "object" }
+					));
+				}
+			}
+		}
+		// Goal tiles are meant to be covered by rocks, unless told otherwise.
+		let has_goals = grid
+			.tiles
+			.iter()
+			.any(|tile| matches!(tile.ground, Ground::Goal));
+		if win_condition.is_none() && has_goals {
+			win_condition = Some(WinCondition::CoverAllGoals { kind: ObjKind::Rock });
+		}
+		let id = id.map(|(id, _)| id).unwrap_or_else(|| {
+			error_messages.push(format!(
+				"structural error: missing id, using \"{fallback_id}\" instead"
+			));
+			fallback_id.to_string()
+		});
 		Level {
 			grid,
 			id,
@@ -779,93 +2242,806 @@ impl Level {
 			notes,
 			entry_coords,
 			entry_direction,
+			named_entries,
+			exiting_kinds,
+			omnidirectional,
+			tick_order,
+			step_limit,
+			win_condition,
+			path: None,
+		}
+	}
+
+	/// The level in the level file format, such that `load_from_text` gives it back.
+	/// None if something in it goes in a direction that cannot be written
+	/// (only the four directions along the grid axes can).
+	fn save_to_text(&self) -> Option<String> {
+		let mut text = String::new();
+		text += &format!("name {}\n", self.name);
+		text += &format!("id {}\n", self.id);
+		if (self.grid.w, self.grid.h) != (Grid::DEFAULT_W, Grid::DEFAULT_H) {
+			text += &format!("size {} {}\n", self.grid.w, self.grid.h);
+		}
+		if self.grid.wrap {
+			text += "wrap\n";
+		}
+		if self.omnidirectional {
+			text += "omnidirectional\n";
+		}
+		if self.tick_order != TickOrder::RowMajor {
+			text += &format!("tick_order {}\n", self.tick_order.descr());
+		}
+		if !self.exiting_kinds.is_empty() {
+			let kinds: Vec<_> = self
+				.exiting_kinds
+				.iter()
+				.map(|kind| kind.descr())
+				.collect::<Option<_>>()?;
+			text += &format!("exiting_objects {}\n", kinds.join(" "));
+		}
+		if let Some(step_limit) = self.step_limit {
+			text += &format!("step_limit {step_limit}\n");
+		}
+		match &self.win_condition {
+			None => {},
+			Some(WinCondition::CollectAllCheese) => text += "goal collect_all_cheese\n",
+			Some(WinCondition::CoverAllGoals { kind }) => {
+				text += &format!("goal cover_goals:{}\n", kind.descr()?)
+			},
+		}
+
+		// Each distinct tile content gets its own character in the grid art.
+		let tile_content = |tile: &Tile| {
+			let exits: Vec<_> = tile
+				.exits
+				.iter()
+				.map(|exit| {
+					(
+						exit.direction,
+						exit.dst_level_id.clone(),
+						exit.entry_name.clone(),
+					)
+				})
+				.collect();
+			(
+				tile.ground.clone(),
+				tile.obj.as_ref().map(|obj| obj.kind.clone()),
+				exits,
+			)
+		};
+		let mut contents = vec![(Ground::Grass, None, vec![])];
+		let mut characters = vec!['.'];
+		let mut available_characters =
+			"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789"
+				.chars()
+				.chain((0x100..).filter_map(char::from_u32));
+		text += "grid\n";
+		for grid_y in 0..self.grid.h {
+			let mut row = vec![];
+			for grid_x in 0..self.grid.w {
+				let content = tile_content(self.grid.get(Point2::from([grid_x, grid_y])).unwrap());
+				let index = match contents.iter().position(|other| *other == content) {
+					Some(index) => index,
+					None => {
+						contents.push(content);
+						characters.push(available_characters.next().unwrap());
+						contents.len() - 1
+					},
+				};
+				row.push(characters[index].to_string());
+			}
+			text += &row.join(" ");
+			text += "\n";
+		}
+		for ((ground, obj_kind, exits), character) in contents.iter().zip(characters.iter()) {
+			if let Some(obj_kind) = obj_kind {
+				text += &format!("obj {character} {}\n", obj_kind.descr()?);
+			}
+			// Grass is the default that is not written.
+			if *ground != Ground::Grass {
+				text += &format!("ground {character} {}\n", ground.descr()?);
+			}
+			for (direction, dst_level_id, entry_name) in exits {
+				let entry_name = match entry_name {
+					Some(entry_name) => format!(" {entry_name}"),
+					None => "".to_string(),
+				};
+				text += &format!(
+					"exit {character} {} {dst_level_id}{entry_name}\n",
+					direction_descr(*direction)?
+				);
+			}
+		}
+
+		text += &format!(
+			"entry_at {} {} {}\n",
+			self.entry_coords.x,
+			self.entry_coords.y,
+			direction_descr(self.entry_direction)?
+		);
+		for entry in self.named_entries.iter() {
+			text += &format!(
+				"entry_at {} {} {} {}\n",
+				entry.coords.x,
+				entry.coords.y,
+				direction_descr(entry.direction)?,
+				entry.name
+			);
+		}
+		for grid_y in 0..self.grid.h {
+			for grid_x in 0..self.grid.w {
+				let tile = self.grid.get(Point2::from([grid_x, grid_y])).unwrap();
+				if let Some(tag) = tile.obj.as_ref().and_then(|obj| obj.tag.as_ref()) {
+					text += &format!("tag {grid_x} {grid_y} {tag}\n");
+				}
+			}
+		}
+		for note in self.notes.iter() {
+			let depth = match note.depth {
+				NoteDepth::Front => "front",
+				NoteDepth::Back => "back",
+			};
+			let page = match note.page {
+				Some(page) => format!(" page {page}"),
+				None => "".to_string(),
+			};
+			let width = match note.width {
+				Some(width) => format!(" width {width}"),
+				None => "".to_string(),
+			};
+			let color = if note.color == Color::BLACK {
+				"".to_string()
+			} else {
+				format!(" color {}", note_color_descr(note.color))
+			};
+			let has_box = if note.has_box { " box" } else { "" };
+			let anchor = match &note.anchor {
+				Some(anchor) => format!(" on {anchor}"),
+				None => "".to_string(),
+			};
+			let note_text = note.text.replace(';', ";;").replace('\n', ";");
+			let options = format!("{page}{width}{color}{has_box}{anchor}");
+			// The `|` ending the options is also needed when the text has one of its own.
+			let separator = if options.is_empty() && !note_text.split(' ').any(|word| word == "|") {
+				""
+			} else {
+				" |"
+			};
+			text += &format!(
+				"note {} {} {} {depth}{options}{separator} {note_text}\n",
+				note.coords.x, note.coords.y, note.scale
+			);
 		}
+		Some(text)
 	}
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 enum NoteDepth {
 	Front,
 	Back,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 struct Note {
 	coords: Point2<i32>,
 	text: String,
 	scale: f32,
 	depth: NoteDepth,
+	/// Notes with a page are only shown when that page is the current one,
+	/// notes without a page are always shown.
+	page: Option<u32>,
+	/// The text wraps to fit in that many pixels, it does not wrap if there is no width.
+	width: Option<f32>,
+	color: Color,
+	/// A box is drawn behind the text so that it can be read over anything.
+	has_box: bool,
+	/// Tag of the object that the note follows, its coords are then relative to the object.
+	/// The note disappears with the object.
+	anchor: Option<String>,
 }
 
-struct Game {
-	all_levels: HashMap<String, Level>,
-	level: Level,
+/// State of the current attempt that undo and redo can get back to.
+struct Snapshot {
 	grid: Grid,
-	notes: Vec<Note>,
-	rays: Vec<Ray>,
-	rays_animation: Option<RaysAnimation>,
-	spritesheet: Image,
-	cheese_count: u32,
-	cheese_count_got_here: u32,
+	facing: IVec2,
 	step_count: u32,
-	step_count_at_level_start: u32,
-	reset_count: u32,
+	cheese_count_got_here: u32,
+	sent_objects: Vec<(ObjKind, Exit)>,
+	inputs: Vec<Input>,
 }
 
-#[derive(Parser)]
-#[command(color = clap::ColorChoice::Auto)]
-struct CommandLineSettings {
-	#[arg(long = "level", short = 'l', value_name = "LEVEL_ID")]
-	level_id: Option<String>,
+/// Something the player does that advances the level, what replays are made of.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Input {
+	Move(IVec2),
+	Shoot,
 }
 
-impl Game {
-	pub fn new(ctx: &mut Context) -> GameResult<Game> {
-		let settings = CommandLineSettings::parse();
-		let mut all_levels = HashMap::new();
-		let test_level = Level::test();
-		all_levels.insert(test_level.id.clone(), test_level);
-		for level_file in std::fs::read_dir("levels").unwrap() {
-			let level_file = level_file.unwrap();
-			let level_text = std::fs::read_to_string(level_file.path()).unwrap();
-			let level = Level::load_from_text(&level_text);
-			let level_id = level.id.clone();
-			all_levels.insert(level_id, level);
-		}
-		let level_id = settings.level_id.as_deref().unwrap_or("test");
-		let level = all_levels.get(level_id).unwrap().clone();
-		let grid = level.grid.clone();
-		let notes = level.notes.clone();
-		let mut game = Game {
-			all_levels,
-			level,
-			grid,
-			notes,
-			rays: vec![],
-			rays_animation: None,
-			spritesheet: Image::from_bytes(ctx, include_bytes!("../assets/spritesheet.png"))?,
-			cheese_count: 0,
-			cheese_count_got_here: 0,
-			step_count: 0,
-			step_count_at_level_start: 0,
-			reset_count: 0,
-		};
-		game.go_to_level(level_id);
-		Ok(game)
+impl Input {
+	fn descr(self) -> Option<&'static str> {
+		match self {
+			Input::Move(direction) => direction_descr(direction),
+			Input::Shoot => Some("shoot"),
+		}
 	}
 
-	fn clear_processed_flags(&mut self) {
-		for tile in self.grid.tiles.iter_mut() {
-			if let Some(obj) = &mut tile.obj {
-				obj.processed = false;
-			}
+	fn from_descr(descr: &str) -> Option<Input> {
+		match descr {
+			"shoot" => Some(Input::Shoot),
+			_ => direction_from_descr(descr).map(Input::Move),
 		}
 	}
-	fn clear_moved_flags(&mut self) {
-		for tile in self.grid.tiles.iter_mut() {
-			if let Some(obj) = &mut tile.obj {
-				obj.moved = false;
-			}
+}
+
+/// The inputs of an attempt at a level, from its start.
+/// Saved as text, the level id on the first line then one input per line.
+struct Replay {
+	level_id: String,
+	inputs: Vec<Input>,
+}
+
+impl Replay {
+	/// Where the best replay of each completed level is saved.
+	fn path(level_id: &str) -> PathBuf {
+		Path::new("replays").join(format!("{level_id}.txt"))
+	}
+
+	fn load(path: &Path) -> Result<Replay, String> {
+		let text = std::fs::read_to_string(path)
+			.map_err(|error| format!("failed to read replay {}: {error}", path.display()))?;
+		let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+		let level_id = lines
+			.next()
+			.ok_or_else(|| format!("replay {} is empty", path.display()))?
+			.to_string();
+		let inputs = lines
+			.map(|line| {
+				Input::from_descr(line)
+					.ok_or_else(|| format!("unknown input \"{line}\" in replay {}", path.display()))
+			})
+			.collect::<Result<_, _>>()?;
+		Ok(Replay { level_id, inputs })
+	}
+
+	fn save(&self, path: &Path) -> Result<(), String> {
+		if let Some(directory) = path.parent() {
+			std::fs::create_dir_all(directory).map_err(|error| error.to_string())?;
+		}
+		let mut text = format!("{}\n", self.level_id);
+		for input in self.inputs.iter() {
+			let descr = input.descr().ok_or("a move cannot be written")?;
+			text += &format!("{descr}\n");
+		}
+		std::fs::write(path, text).map_err(|error| error.to_string())
+	}
+
+	/// The inputs of the best solution of the given level, if it was completed.
+	fn load_best(level_id: &str) -> Option<Vec<Input>> {
+		let path = Replay::path(level_id);
+		if !path.is_file() {
+			return None;
+		}
+		match Replay::load(&path) {
+			Ok(replay) if replay.level_id == level_id => Some(replay.inputs),
+			Ok(_) => None,
+			Err(error) => {
+				println!("warning: ignoring {error}");
+				None
+			},
+		}
+	}
+}
+
+/// A translucent player retracing the best solution of the level,
+/// that plays as many inputs as the player did in the current attempt.
+struct Ghost {
+	inputs: Vec<Input>,
+	/// The level as it was when the attempt started.
+	start: Simulation,
+	sim: Simulation,
+	/// Number of inputs played in `sim` since `start`.
+	played: usize,
+}
+
+impl Ghost {
+	fn new(inputs: Vec<Input>, start: &Simulation) -> Ghost {
+		Ghost { inputs, start: start.clone(), sim: start.clone(), played: 0 }
+	}
+
+	fn restart(&mut self, start: &Simulation) {
+		self.start = start.clone();
+		self.sim = start.clone();
+		self.played = 0;
+	}
+
+	/// Plays the inputs up to the given count (or until there are no more),
+	/// starting over if it is behind where it is (after an undo).
+	fn catch_up(&mut self, input_count: usize) {
+		if input_count < self.played {
+			self.sim = self.start.clone();
+			self.played = 0;
+		}
+		while self.played < input_count.min(self.inputs.len()) {
+			match self.inputs[self.played] {
+				Input::Move(direction) => {
+					self.sim.player_move(direction);
+				},
+				Input::Shoot => {
+					self.sim.player_shoot();
+					self.sim.settle();
+				},
+			}
+			self.sim.sound_effects.clear();
+			self.sim.cheese_eaten_at.clear();
+			self.played += 1;
+		}
+	}
+}
+
+/// Frames captured to be saved as an animated GIF.
+struct Recording {
+	frames: Vec<RecordedFrame>,
+	last_frame_time: Option<Instant>,
+}
+
+/// The window can be resized during a recording, so each frame has its own size.
+struct RecordedFrame {
+	/// RGBA pixels, downscaled.
+	pixels: Vec<u8>,
+	width: u16,
+	height: u16,
+}
+
+impl Recording {
+	const FRAME_DURATION: Duration = Duration::from_millis(100);
+	/// Caps the recording at a few seconds to keep memory usage bounded.
+	const MAX_FRAME_COUNT: usize = 50;
+	/// Each side of the window is divided by that.
+	const DOWNSCALE: u32 = 2;
+
+	fn new() -> Recording {
+		Recording { frames: vec![], last_frame_time: None }
+	}
+
+	fn encode_gif(self, path: &Path) -> Result<(), String> {
+		if let Some(directory) = path.parent() {
+			std::fs::create_dir_all(directory).map_err(|error| error.to_string())?;
+		}
+		let file = std::fs::File::create(path).map_err(|error| error.to_string())?;
+		// Frames smaller than the biggest one are drawn in its top left corner.
+		let width = self
+			.frames
+			.iter()
+			.map(|frame| frame.width)
+			.max()
+			.unwrap_or(0);
+		let height = self
+			.frames
+			.iter()
+			.map(|frame| frame.height)
+			.max()
+			.unwrap_or(0);
+		let mut encoder =
+			gif::Encoder::new(file, width, height, &[]).map_err(|error| error.to_string())?;
+		encoder
+			.set_repeat(gif::Repeat::Infinite)
+			.map_err(|error| error.to_string())?;
+		for mut recorded_frame in self.frames {
+			let mut frame = gif::Frame::from_rgba_speed(
+				recorded_frame.width,
+				recorded_frame.height,
+				&mut recorded_frame.pixels,
+				10,
+			);
+			frame.delay = (Recording::FRAME_DURATION.as_millis() / 10) as u16;
+			encoder
+				.write_frame(&frame)
+				.map_err(|error| error.to_string())?;
+		}
+		Ok(())
+	}
+}
+
+/// Path to a file next to the executable.
+fn path_next_to_exe(file_name: &str) -> PathBuf {
+	match std::env::current_exe() {
+		Ok(exe_path) => exe_path.with_file_name(file_name),
+		Err(_) => PathBuf::from(file_name),
+	}
+}
+
+/// Where the best step count of each completed level is saved, next to the executable.
+fn scores_path() -> PathBuf {
+	path_next_to_exe("scores.ron")
+}
+
+/// Best step count of each completed level, by level id.
+/// A missing or corrupt scores file just means no scores yet.
+fn load_best_steps() -> HashMap<String, u32> {
+	let path = scores_path();
+	let Ok(text) = std::fs::read_to_string(&path) else {
+		return HashMap::new();
+	};
+	match ron::from_str(&text) {
+		Ok(best_steps) => best_steps,
+		Err(error) => {
+			println!(
+				"warning: ignoring corrupt scores file {}: {error}",
+				path.display()
+			);
+			HashMap::new()
+		},
+	}
+}
+
+fn save_best_steps(best_steps: &HashMap<String, u32>) {
+	let path = scores_path();
+	let result = ron::to_string(best_steps)
+		.map_err(|error| error.to_string())
+		.and_then(|text| std::fs::write(&path, text).map_err(|error| error.to_string()));
+	if let Err(error) = result {
+		println!(
+			"warning: failed to save scores to {}: {error}",
+			path.display()
+		);
+	}
+}
+
+/// Settings read from `config.ron` (next to the executable),
+/// settings missing from the file keep their default values.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Config {
+	keybindings: Keybindings,
+	/// Changed in the game, and saved back to the config file.
+	audio: AudioSettings,
+}
+
+impl Config {
+	fn path() -> PathBuf {
+		path_next_to_exe("config.ron")
+	}
+
+	/// A missing config file just means default settings.
+	fn load() -> Result<Config, String> {
+		let path = Config::path();
+		let Ok(text) = std::fs::read_to_string(&path) else {
+			return Ok(Config::default());
+		};
+		ron::from_str(&text)
+			.map_err(|error| format!("config file {} is corrupt: {error}", path.display()))
+	}
+
+	fn save(&self) -> Result<(), String> {
+		let path = Config::path();
+		let text = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+			.map_err(|error| error.to_string())?;
+		std::fs::write(&path, text)
+			.map_err(|error| format!("failed to write config file {}: {error}", path.display()))
+	}
+}
+
+/// Volume of the music and sound effects, saved in the config file.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+struct AudioSettings {
+	/// Between 0 and 1.
+	volume: f32,
+	muted: bool,
+}
+
+impl Default for AudioSettings {
+	fn default() -> AudioSettings {
+		AudioSettings { volume: 0.7, muted: false }
+	}
+}
+
+impl AudioSettings {
+	const VOLUME_STEP: f32 = 0.1;
+
+	fn effective_volume(self) -> f32 {
+		if self.muted {
+			0.0
+		} else {
+			self.volume
+		}
+	}
+
+	/// The rest of the config file is read again to be kept as it is (keybindings that
+	/// the game did not accept included), a corrupt config file is not written over.
+	fn save(self) -> Result<(), String> {
+		let mut config = Config::load()?;
+		config.audio = self;
+		config.save()
+	}
+}
+
+/// Something the player can do by pressing a key.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Action {
+	Move(IVec2),
+	Shoot,
+	Reset,
+	Undo,
+	Redo,
+}
+
+/// Keys bound to each action, read from the config file so that they can be changed
+/// without recompiling. Actions missing from the file keep their default keys.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct Keybindings {
+	up: Vec<VirtualKeyCode>,
+	down: Vec<VirtualKeyCode>,
+	left: Vec<VirtualKeyCode>,
+	right: Vec<VirtualKeyCode>,
+	shoot: Vec<VirtualKeyCode>,
+	reset: Vec<VirtualKeyCode>,
+	undo: Vec<VirtualKeyCode>,
+	redo: Vec<VirtualKeyCode>,
+}
+
+impl Default for Keybindings {
+	fn default() -> Keybindings {
+		use VirtualKeyCode as Key;
+		Keybindings {
+			up: vec![Key::Up, Key::W],
+			down: vec![Key::Down, Key::S],
+			left: vec![Key::Left, Key::A],
+			right: vec![Key::Right, Key::D],
+			shoot: vec![Key::Space, Key::Return],
+			reset: vec![Key::R],
+			undo: vec![Key::U],
+			redo: vec![Key::Y],
+		}
+	}
+}
+
+impl Keybindings {
+	/// Keys that do something that cannot be rebound, so no action can be bound to them.
+	const RESERVED_KEYS: [VirtualKeyCode; 15] = [
+		VirtualKeyCode::Escape,
+		VirtualKeyCode::P,
+		VirtualKeyCode::Tab,
+		VirtualKeyCode::F3,
+		VirtualKeyCode::G,
+		VirtualKeyCode::N,
+		VirtualKeyCode::M,
+		VirtualKeyCode::Plus,
+		VirtualKeyCode::Equals,
+		VirtualKeyCode::NumpadAdd,
+		VirtualKeyCode::Minus,
+		VirtualKeyCode::NumpadSubtract,
+		VirtualKeyCode::F5,
+		VirtualKeyCode::F9,
+		VirtualKeyCode::F11,
+	];
+
+	fn bindings(&self) -> [(Action, &[VirtualKeyCode]); 8] {
+		[
+			(Action::Move(IVec2::from([0, -1])), &self.up),
+			(Action::Move(IVec2::from([0, 1])), &self.down),
+			(Action::Move(IVec2::from([-1, 0])), &self.left),
+			(Action::Move(IVec2::from([1, 0])), &self.right),
+			(Action::Shoot, &self.shoot),
+			(Action::Reset, &self.reset),
+			(Action::Undo, &self.undo),
+			(Action::Redo, &self.redo),
+		]
+	}
+
+	fn action(&self, key: VirtualKeyCode) -> Option<Action> {
+		self
+			.bindings()
+			.into_iter()
+			.find(|(_action, keys)| keys.contains(&key))
+			.map(|(action, _keys)| action)
+	}
+
+	/// A key bound to two things would make one of them unreachable.
+	fn check(&self) -> Result<(), String> {
+		let mut action_of_key = HashMap::new();
+		for (action, keys) in self.bindings() {
+			for &key in keys {
+				if Keybindings::RESERVED_KEYS.contains(&key) {
+					return Err(format!("key {key:?} is reserved"));
+				}
+				if let Some(other_action) = action_of_key.insert(key, action) {
+					if other_action != action {
+						return Err(format!(
+							"key {key:?} is bound to both {other_action:?} and {action:?}"
+						));
+					}
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+/// A corrupt config file or invalid keybindings are ignored with a warning,
+/// printed and added to `warnings` (to be shown in the game).
+fn load_config(warnings: &mut Vec<String>) -> (Keybindings, AudioSettings) {
+	let config = match Config::load() {
+		Ok(config) => config,
+		Err(error) => {
+			let warning = format!("warning: {error}, using the default settings");
+			println!("{warning}");
+			warnings.push(warning);
+			Config::default()
+		},
+	};
+	let keybindings = match config.keybindings.check() {
+		Ok(()) => config.keybindings,
+		Err(error) => {
+			let warning = format!(
+				"warning: ignoring keybindings of config file {}, using the default ones: {error}",
+				Config::path().display()
+			);
+			println!("{warning}");
+			warnings.push(warning);
+			Keybindings::default()
+		},
+	};
+	(keybindings, config.audio)
+}
+
+/// Something that happened in a simulation and that can be heard.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+enum SoundEffect {
+	Move,
+	Push,
+	FailToMove,
+	Cheese,
+	Door,
+	Shoot,
+	Explosion,
+}
+
+impl SoundEffect {
+	const ALL: [SoundEffect; 7] = [
+		SoundEffect::Move,
+		SoundEffect::Push,
+		SoundEffect::FailToMove,
+		SoundEffect::Cheese,
+		SoundEffect::Door,
+		SoundEffect::Shoot,
+		SoundEffect::Explosion,
+	];
+
+	fn wav_data(self) -> &'static [u8] {
+		match self {
+			SoundEffect::Move => include_bytes!("../assets/sounds/move.wav"),
+			SoundEffect::Push => include_bytes!("../assets/sounds/push.wav"),
+			SoundEffect::FailToMove => include_bytes!("../assets/sounds/fail.wav"),
+			SoundEffect::Cheese => include_bytes!("../assets/sounds/cheese.wav"),
+			SoundEffect::Door => include_bytes!("../assets/sounds/door.wav"),
+			SoundEffect::Shoot => include_bytes!("../assets/sounds/ray.wav"),
+			SoundEffect::Explosion => include_bytes!("../assets/sounds/explosion.wav"),
+		}
+	}
+}
+
+/// The rules of the game applied to a level being played, without anything about rendering
+/// or windowing so that it can run headless.
+#[derive(Clone)]
+struct Simulation {
+	grid: Grid,
+	rays: Vec<Ray>,
+	/// Bombs hit by a ray or caught in a blast, they explode together once no ray is flying
+	/// (after a short fuse when playing).
+	lit_bombs: Vec<Point2<i32>>,
+	/// Direction of the last move of the player, it only shoots the raygun in front of it
+	/// (unless the level is `omnidirectional`).
+	facing: IVec2,
+	omnidirectional: bool,
+	tick_order: TickOrder,
+	step_limit: Option<u32>,
+	win_condition: Option<WinCondition>,
+	/// Steps taken since the start of the level.
+	step_count: u32,
+	/// Cheese collected since the start of the attempt, it is provisional:
+	/// it is lost on reset, death or reload, and only counts once the player exits the level.
+	cheese_count_got_here: u32,
+	/// Set when a player takes an exit.
+	taken_exit: Option<Exit>,
+	exiting_kinds: Vec<ObjKind>,
+	/// Objects that went through an exit, they get to the level it leads to
+	/// only if the player leaves this level too (like the cheese got here).
+	sent_objects: Vec<(ObjKind, Exit)>,
+	/// What happened that can be heard, for the game to play the sounds and clear.
+	sound_effects: Vec<SoundEffect>,
+	/// Where cheese was eaten, for the game to show it and clear.
+	cheese_eaten_at: Vec<Point2<i32>>,
+	/// Set when a player got caught by a hunter, the attempt is lost.
+	player_died: bool,
+}
+
+/// What happened during a player move.
+struct MoveOutcome {
+	/// Objects that moved, with the coords they were at and the coords they got to.
+	moved: Vec<(ObjKind, Point2<i32>, Point2<i32>)>,
+	/// The exit the player took, if any.
+	exited_to: Option<Exit>,
+	cheese_gained: u32,
+	/// Some object attempted to move but was blocked.
+	some_move_failed: bool,
+	/// Some object or ground is different (moved objects are not the only way the board changes,
+	/// a key and a door disappearing or a sapling growing also count).
+	board_changed: bool,
+}
+
+impl Simulation {
+	/// The given level with the player just entering it through its unnamed entry.
+	fn new(level: &Level) -> Simulation {
+		Simulation::new_at_entry(level, level.entry_coords, level.entry_direction)
+	}
+
+	/// Puts an object that was sent to this level at the given entry, or in front of it
+	/// if the player is already there. If there is no room, the object is lost.
+	fn place_at_entry(&mut self, kind: ObjKind, entry_coords: Point2<i32>, entry_direction: IVec2) {
+		let in_front = IVec2::from(entry_coords) + entry_direction;
+		for coords in [entry_coords, in_front.into()] {
+			if let Some(tile) = self.grid.get_mut(coords) {
+				if tile.obj.is_none() {
+					tile.obj = Some(Obj::from_kind(kind));
+					return;
+				}
+			}
+		}
+	}
+
+	/// The given level with the player just entering it at `entry_coords`.
+	fn new_at_entry(level: &Level, entry_coords: Point2<i32>, entry_direction: IVec2) -> Simulation {
+		let mut grid = level.grid.clone();
+		grid.get_mut(entry_coords).unwrap().obj = Some(Obj::from_kind(ObjKind::Player));
+		grid
+			.get_mut(entry_coords)
+			.unwrap()
+			.obj
+			.as_mut()
+			.unwrap()
+			.animation = Animation::CommingFrom {
+			src: (IVec2::from(entry_coords) - entry_direction).into(),
+			time_start: Instant::now(),
+			duration: Duration::from_secs_f32(0.05),
+		};
+		let mut sim = Simulation {
+			grid,
+			rays: vec![],
+			lit_bombs: vec![],
+			facing: entry_direction,
+			omnidirectional: level.omnidirectional,
+			tick_order: level.tick_order,
+			step_limit: level.step_limit,
+			win_condition: level.win_condition.clone(),
+			step_count: 0,
+			cheese_count_got_here: 0,
+			taken_exit: None,
+			exiting_kinds: level.exiting_kinds.clone(),
+			sent_objects: vec![],
+			sound_effects: vec![],
+			cheese_eaten_at: vec![],
+			player_died: false,
+		};
+		sim.handle_plates();
+		sim.handle_crumbling_floors();
+		sim
+	}
+
+	fn clear_processed_flags(&mut self) {
+		for tile in self.grid.tiles.iter_mut() {
+			if let Some(obj) = &mut tile.obj {
+				obj.processed = false;
+			}
+		}
+	}
+	fn clear_moved_flags(&mut self) {
+		for tile in self.grid.tiles.iter_mut() {
+			if let Some(obj) = &mut tile.obj {
+				obj.moved = false;
+			}
 		}
 	}
 	fn clear_animations(&mut self) {
@@ -876,20 +3052,74 @@ impl Game {
 		}
 	}
 
+	/// Number of steps a fire burns before going out.
+	const FIRE_DURATION: u8 = 2;
+
+	/// Fires spread to the trees next to them, then burn for a bit more and go out.
+	/// Trees caught by the fire this step only start to spread it the next step.
+	fn handle_fire(&mut self) {
+		let mut fires = vec![];
+		for grid_y in 0..self.grid.h {
+			for grid_x in 0..self.grid.w {
+				let coords = Point2::from([grid_x, grid_y]);
+				if let Some(Obj { kind: ObjKind::Fire { age }, .. }) =
+					self.grid.get(coords).unwrap().obj
+				{
+					fires.push((coords, age));
+				}
+			}
+		}
+		for (coords, age) in fires {
+			for direction in [(1, 0), (0, 1), (-1, 0), (0, -1)].map(IVec2::from) {
+				let neighboor = self.grid.get_mut((IVec2::from(coords) + direction).into());
+				if let Some(neighboor) = neighboor {
+					if neighboor
+						.obj
+						.as_ref()
+						.is_some_and(|obj| obj.kind == ObjKind::Tree)
+					{
+						neighboor.obj = Some(Obj::from_kind(ObjKind::Fire { age: 0 }));
+					}
+				}
+			}
+			let tile = self.grid.get_mut(coords).unwrap();
+			if age + 1 >= Simulation::FIRE_DURATION {
+				tile.obj = None;
+				tile.ground = Ground::Grass;
+				self.grid.ground_changed();
+			} else {
+				tile.obj = Some(Obj::from_kind(ObjKind::Fire { age: age + 1 }));
+			}
+		}
+	}
+
 	fn handle_sapling(&mut self, can_grow: bool) {
+		let mut ground_changed = false;
 		for tile in self.grid.tiles.iter_mut() {
 			if let Ground::Sapling { stepped_on } = tile.ground {
 				if stepped_on && tile.obj.is_none() && can_grow {
 					tile.ground = Ground::Grass;
 					tile.obj = Some(Obj::from_kind(ObjKind::Tree));
+					ground_changed = true;
 				} else if (!stepped_on) && tile.obj.is_some() {
 					tile.ground = Ground::Sapling { stepped_on: true };
+					ground_changed = true;
 				}
 			}
 		}
+		if ground_changed {
+			self.grid.ground_changed();
+		}
 	}
 
-	fn line_of_sights_to(&self, coords: Point2<i32>, to_what: ObjKind) -> Vec<IVec2> {
+	/// Directions in which the first object in sight is of the given kind.
+	/// With `through_holes`, sight goes through walls with holes like rays do.
+	fn line_of_sights_to(
+		&self,
+		coords: Point2<i32>,
+		to_what: ObjKind,
+		through_holes: bool,
+	) -> Vec<IVec2> {
 		[(1, 0), (0, 1), (-1, 0), (0, -1)]
 			.into_iter()
 			.map(|(dx, dy)| IVec2::from([dx, dy]))
@@ -899,7 +3129,9 @@ impl Game {
 					coords += direction;
 					if let Some(tile) = self.grid.get(coords.into()) {
 						if let Some(obj) = &tile.obj {
-							break obj.kind == to_what;
+							if !(through_holes && obj.kind == ObjKind::WallWithHoles) {
+								break obj.kind == to_what;
+							}
 						}
 					} else {
 						break false;
@@ -909,21 +3141,276 @@ impl Game {
 			.collect()
 	}
 
+	/// All the bunnies flee at once (see `move_together`), so that they do not depend on
+	/// the order they are processed in.
 	fn handle_bunnies(&mut self) {
-		for grid_y in 0..Grid::H {
-			for grid_x in 0..Grid::W {
+		let mut flights = vec![];
+		for coords in self.tick_order_coords(self.facing) {
+			if let Some(obj) = &self.grid.get(coords).unwrap().obj {
+				if obj.kind == ObjKind::Bunny && !obj.processed {
+					let mut scarred_dirs = self.line_of_sights_to(coords, ObjKind::Player, true);
+					scarred_dirs.retain(|&dir| {
+						let tile = self.grid.get((IVec2::from(coords) - dir).into());
+						tile.is_some_and(|tile| {
+							tile.obj.is_none() || tile.obj.as_ref().is_some_and(|obj| obj.can_move())
+						})
+					});
+					let scarred_dir: IVec2 = scarred_dirs.into_iter().sum();
+					if scarred_dir.x.abs() + scarred_dir.y.abs() == 1 {
+						flights.push((coords, -scarred_dir));
+					}
+				}
+			}
+		}
+		self.move_together(flights, |obj| obj.kind == ObjKind::Bunny && !obj.processed);
+	}
+
+	/// Makes the given moves (coords of an object and its direction, in tick order) at once.
+	/// Each object first gets where it is going (on the grid as it is before any move), and
+	/// objects going to the same tile or swapping places bump into each other and all stay.
+	/// Then the moves are made, an object moving into one that moves too waits for it to get
+	/// out of the way (a line of players walks as one), and an object moving into one that
+	/// stays stays too. The tick order decides which of the moves that do not depend on each
+	/// other are made first. `can_move` tells if the object at the coords of a move
+	/// is still the one that has to make it (it was not pushed away by an earlier move).
+	fn move_together(&mut self, moves: Vec<(Point2<i32>, IVec2)>, can_move: impl Fn(&Obj) -> bool) {
+		let mut pending_moves: Vec<(Point2<i32>, IVec2, Point2<i32>)> = moves
+			.into_iter()
+			.map(|(coords, direction)| {
+				let (dst, _teleported_from) = self.move_destination(coords, direction);
+				(coords, direction, self.grid.wrapped(dst.into()))
+			})
+			.collect();
+		// Objects that do not move, the objects moving into them are blocked.
+		let mut staying: Vec<Point2<i32>> = vec![];
+		let all_moves = pending_moves.clone();
+		pending_moves.retain(|&(coords, _direction, dst)| {
+			let conflicting = all_moves.iter().any(|&(other_coords, _, other_dst)| {
+				other_coords != coords
+					&& (other_dst == dst || (other_dst == coords && other_coords == dst))
+			});
+			if conflicting {
+				staying.push(coords);
+			}
+			!conflicting
+		});
+		for &(coords, _direction, dst) in all_moves.iter() {
+			if staying.contains(&coords) {
+				self.fail_to_move(coords, dst);
+			}
+		}
+		while !pending_moves.is_empty() {
+			// A move into an object that still has to move waits, unless all the moves left
+			// are waiting on each other (a ring of players around a wrapping grid).
+			let is_waiting = |&(_coords, _direction, dst): &(Point2<i32>, IVec2, Point2<i32>)| {
+				pending_moves
+					.iter()
+					.any(|&(other_coords, _, _)| other_coords == dst)
+			};
+			let index = pending_moves
+				.iter()
+				.position(|pending_move| !is_waiting(pending_move))
+				.unwrap_or(0);
+			let (coords, direction, dst) = pending_moves.remove(index);
+			if !self
+				.grid
+				.get(coords)
+				.unwrap()
+				.obj
+				.as_ref()
+				.is_some_and(&can_move)
+			{
+				continue;
+			}
+			if staying.contains(&dst) {
+				staying.push(coords);
+				self.fail_to_move(coords, dst);
+				continue;
+			}
+			self
+				.grid
+				.get_mut(coords)
+				.unwrap()
+				.obj
+				.as_mut()
+				.unwrap()
+				.processed = true;
+			self.obj_move(coords, direction, None);
+			// What may have come to these coords since (a pulled rope for example) is not
+			// the object that just made its move.
+			let stayed = self
+				.grid
+				.get(coords)
+				.unwrap()
+				.obj
+				.as_ref()
+				.is_some_and(|obj| obj.processed);
+			if stayed {
+				staying.push(coords);
+			}
+		}
+	}
+
+	/// All the coords of the grid, in the order the level processes the players and bunnies
+	/// for a move in the given direction.
+	fn tick_order_coords(&self, direction: IVec2) -> Vec<Point2<i32>> {
+		let mut coords_list = vec![];
+		match self.tick_order {
+			TickOrder::ColumnMajor => {
+				for grid_x in 0..self.grid.w {
+					for grid_y in 0..self.grid.h {
+						coords_list.push(Point2::from([grid_x, grid_y]));
+					}
+				}
+			},
+			TickOrder::RowMajor | TickOrder::DistanceFromInput => {
+				for grid_y in 0..self.grid.h {
+					for grid_x in 0..self.grid.w {
+						coords_list.push(Point2::from([grid_x, grid_y]));
+					}
+				}
+			},
+		}
+		if self.tick_order == TickOrder::DistanceFromInput {
+			coords_list.sort_by_key(|coords| -IVec2::from(*coords).dot(direction));
+		}
+		coords_list
+	}
+
+	/// Crumbling floors crack when something is on them, and crumble into pits
+	/// once it left.
+	fn handle_crumbling_floors(&mut self) {
+		let mut ground_changed = false;
+		for tile in self.grid.tiles.iter_mut() {
+			if let Ground::Crumbling { stepped } = tile.ground {
+				if stepped && tile.obj.is_none() {
+					tile.ground = Ground::Pit;
+					ground_changed = true;
+				} else if !stepped && tile.obj.is_some() {
+					tile.ground = Ground::Crumbling { stepped: true };
+					ground_changed = true;
+				}
+			}
+		}
+		if ground_changed {
+			self.grid.ground_changed();
+		}
+	}
+
+	/// Objects that got into pits or water fall or sink in and are gone, players that fell in die.
+	/// A rock that got into a pit or water fills it, it becomes grass that can be walked on.
+	fn handle_pits(&mut self) {
+		let mut ground_changed = false;
+		for tile in self.grid.tiles.iter_mut() {
+			if matches!(tile.ground, Ground::Pit | Ground::Water) {
+				match tile.obj.take().map(|obj| obj.kind) {
+					Some(ObjKind::Rock) => {
+						tile.ground = Ground::Grass;
+						ground_changed = true;
+					},
+					Some(ObjKind::Player) => self.player_died = true,
+					_ => {},
+				}
+			}
+		}
+		if ground_changed {
+			self.grid.ground_changed();
+		}
+	}
+
+	/// Objects that got on spikes are destroyed, players that got on spikes die.
+	fn handle_spikes(&mut self) {
+		for tile in self.grid.tiles.iter_mut() {
+			if matches!(tile.ground, Ground::Spikes) {
+				if let Some(obj) = tile.obj.take() {
+					if obj.kind == ObjKind::Player {
+						self.player_died = true;
+					}
+				}
+			}
+		}
+	}
+
+	/// The first object in the given direction from `coords` and how far it is.
+	/// The line stops at the edge of the grid, even if the grid wraps.
+	fn first_obj_in_line(
+		&self,
+		coords: Point2<i32>,
+		direction: IVec2,
+	) -> Option<(Point2<i32>, i32)> {
+		let mut coords = IVec2::from(coords);
+		for distance in 1..=self.grid.w.max(self.grid.h) {
+			coords += direction;
+			let in_grid =
+				0 <= coords.x && coords.x < self.grid.w && 0 <= coords.y && coords.y < self.grid.h;
+			if !in_grid {
+				return None;
+			}
+			if self.grid.get(coords.into())?.obj.is_some() {
+				return Some((coords.into(), distance));
+			}
+		}
+		None
+	}
+
+	/// Magnets pull the closest rock they see in each direction by one tile toward themselves,
+	/// a rock already next to its magnet stays there. The pulls on a rock add up,
+	/// so a rock pulled by two magnets in different directions does not move.
+	fn handle_magnets(&mut self) {
+		let mut pulls: HashMap<(i32, i32), IVec2> = HashMap::new();
+		for grid_y in 0..self.grid.h {
+			for grid_x in 0..self.grid.w {
+				let coords = Point2::from([grid_x, grid_y]);
+				let is_magnet = self
+					.grid
+					.get(coords)
+					.unwrap()
+					.obj
+					.as_ref()
+					.is_some_and(|obj| obj.kind == ObjKind::Magnet);
+				if !is_magnet {
+					continue;
+				}
+				for direction in [(1, 0), (0, 1), (-1, 0), (0, -1)].map(IVec2::from) {
+					let Some((rock_coords, distance)) = self.first_obj_in_line(coords, direction) else {
+						continue;
+					};
+					let is_rock = self
+						.grid
+						.get(rock_coords)
+						.unwrap()
+						.obj
+						.as_ref()
+						.is_some_and(|obj| obj.kind == ObjKind::Rock);
+					if is_rock && distance >= 2 {
+						*pulls.entry((rock_coords.x, rock_coords.y)).or_default() -= direction;
+					}
+				}
+			}
+		}
+		// Sorted so that the rocks are moved in reading order, whatever the order of the map.
+		let mut pulls: Vec<_> = pulls.into_iter().collect();
+		pulls.sort_by_key(|&((x, y), _)| (y, x));
+		for ((x, y), pull) in pulls {
+			if pull.x.abs() + pull.y.abs() == 1 {
+				self.obj_move(Point2::from([x, y]), pull, Some(ObjKind::Magnet));
+			}
+		}
+	}
+
+	/// Hunters move toward the player they have line of sight to, the opposite of bunnies.
+	/// A hunter moving into a player catches it, taking its place.
+	fn handle_hunters(&mut self) {
+		for grid_y in 0..self.grid.h {
+			for grid_x in 0..self.grid.w {
 				let coords = Point2::from([grid_x, grid_y]);
 				if let Some(obj) = &self.grid.get(coords).unwrap().obj {
-					if obj.kind == ObjKind::Bunny && !obj.processed {
-						let mut scarred_dirs = self.line_of_sights_to(coords, ObjKind::Player);
-						scarred_dirs.retain(|&dir| {
-							let tile = self.grid.get((IVec2::from(coords) - dir).into());
-							tile.is_some_and(|tile| {
-								tile.obj.is_none() || tile.obj.as_ref().is_some_and(|obj| obj.can_move())
-							})
-						});
-						let scarred_dir: IVec2 = scarred_dirs.into_iter().sum();
-						if scarred_dir.x.abs() + scarred_dir.y.abs() == 1 {
+					if obj.kind == ObjKind::Hunter && !obj.processed {
+						let hunting_dir: IVec2 = self
+							.line_of_sights_to(coords, ObjKind::Player, false)
+							.into_iter()
+							.sum();
+						if hunting_dir.x.abs() + hunting_dir.y.abs() == 1 {
 							self
 								.grid
 								.get_mut(coords)
@@ -932,7 +3419,21 @@ impl Game {
 								.as_mut()
 								.unwrap()
 								.processed = true;
-							self.obj_move(coords, -scarred_dir, false);
+							// There is nothing between a hunter and the player it sees,
+							// so what is in front of it is either nothing or the player.
+							let coords_dst = self
+								.grid
+								.wrapped((IVec2::from(coords) + hunting_dir).into());
+							let tile_dst = self.grid.get_mut(coords_dst).unwrap();
+							if tile_dst
+								.obj
+								.as_ref()
+								.is_some_and(|obj| obj.kind == ObjKind::Player)
+							{
+								tile_dst.obj = None;
+								self.player_died = true;
+							}
+							self.obj_move(coords, hunting_dir, None);
 						}
 					}
 				}
@@ -940,35 +3441,140 @@ impl Game {
 		}
 	}
 
-	fn obj_move(&mut self, coords: Point2<i32>, direction: IVec2, pushed: bool) {
-		let mut coords_dst = IVec2::from(coords) + direction;
-		while self
+	/// Moves the object at `coords` (pushing, pulling, etc. other objects in the process).
+	/// If the object is pushed then `pushed_by` is the kind of the object that pushes it.
+	fn obj_move(&mut self, coords: Point2<i32>, direction: IVec2, pushed_by: Option<ObjKind>) {
+		let coords = self.grid.wrapped(coords);
+		let mover_is_rope = self
 			.grid
-			.get(coords_dst.into())
-			.is_some_and(|tile| tile.obj.is_none() && matches!(tile.ground, Ground::Ice))
-			&& self
-				.grid
-				.get((coords_dst + direction).into())
-				.is_some_and(|tile| tile.obj.is_none())
-		{
-			coords_dst += direction;
+			.get(coords)
+			.and_then(|tile| tile.obj.as_ref())
+			.is_some_and(|obj| matches!(obj.kind, ObjKind::Rope));
+		let moved = self.obj_move_without_pulling(coords, direction, pushed_by.clone(), 0);
+		if !moved || pushed_by.is_some() {
+			return;
 		}
+
+		// What moves away from a rope pulls it along, and a moving rope pulls whatever is
+		// behind it, so a whole chain of ropes follows (with what is at its end).
+		// The chain is walked backward, each link moving into the tile the previous one left.
+		let mut puller_is_rope = mover_is_rope;
+		let mut coords_maybe_pulled = IVec2::from(coords) - direction;
+		for _ in 0..self.grid.w.max(self.grid.h) {
+			let coords_pulled = self.grid.wrapped(coords_maybe_pulled.into());
+			let pulled_is_rope = self
+				.grid
+				.get(coords_pulled)
+				.and_then(|tile| tile.obj.as_ref())
+				.is_some_and(|obj| matches!(obj.kind, ObjKind::Rope));
+			if !(puller_is_rope || pulled_is_rope) {
+				break;
+			}
+			if !self.obj_move_without_pulling(coords_pulled, direction, None, 0) {
+				break;
+			}
+			puller_is_rope = pulled_is_rope;
+			coords_maybe_pulled -= direction;
+		}
+	}
+
+	/// Where an object moving from the given coords in the given direction would get to
+	/// (sliding on ice, going through a teleporter or one-way gates), if nothing blocks it.
+	/// Also gives the teleporter it would come out of, if any.
+	fn move_destination(
+		&self,
+		coords: Point2<i32>,
+		direction: IVec2,
+	) -> (IVec2, Option<Point2<i32>>) {
+		let mut coords_dst =
+			self.slide_destination(coords, IVec2::from(coords) + direction, direction);
+		// Landing on a teleporter means coming out of the other teleporter of its channel,
+		// if there is no room there then the teleporter just blocks like a wall.
+		let mut teleported_from = None;
+		let teleporter_channel = self
+			.grid
+			.get(coords_dst.into())
+			.and_then(|tile| tile.obj.as_ref())
+			.and_then(|obj| match obj.kind {
+				ObjKind::Teleporter { channel } => Some(channel),
+				_ => None,
+			});
+		if let Some(channel) = teleporter_channel {
+			let teleporter_coords = self.grid.wrapped(coords_dst.into());
+			if let Some(other_coords) = self.other_teleporter_coords(teleporter_coords, channel) {
+				let coords_out = IVec2::from(other_coords) + direction;
+				if self
+					.grid
+					.get(coords_out.into())
+					.is_some_and(|tile| tile.obj.is_none())
+				{
+					teleported_from = Some(other_coords);
+					coords_dst = coords_out;
+				}
+			}
+		}
+		// Going through one-way gates in their direction leads to the tile behind them.
+		for _ in 0..self.grid.w.max(self.grid.h) {
+			let gate_direction = self
+				.grid
+				.get(coords_dst.into())
+				.and_then(|tile| tile.obj.as_ref())
+				.and_then(|obj| match obj.kind {
+					ObjKind::OneWay { direction } => Some(direction),
+					_ => None,
+				});
+			if gate_direction != Some(direction)
+				|| self.grid.get((coords_dst + direction).into()).is_none()
+			{
+				break;
+			}
+			coords_dst += direction;
+		}
+		(coords_dst, teleported_from)
+	}
+
+	/// Moves the object at `coords` (pushing other objects in the process) but does not pull
+	/// anything, returns `true` if the object did move.
+	/// `push_depth` is the number of objects pushing this one in a row, a push chain longer
+	/// than the grid can only come from a cycle (through teleporters for example) and fails.
+	fn obj_move_without_pulling(
+		&mut self,
+		coords: Point2<i32>,
+		direction: IVec2,
+		pushed_by: Option<ObjKind>,
+		push_depth: i32,
+	) -> bool {
+		let coords = self.grid.wrapped(coords);
+		let (mut coords_dst, teleported_from) = self.move_destination(coords, direction);
 		let mut shall_move = false;
 		let mut failed_to_move = false;
 		let mut soap_getting_back = None;
 		let mut key_got_in_door = false;
+		let mut player_burnt = false;
+		// A full ring of objects on a wrapping grid would push itself forever.
+		let pushing_full_ring = self.grid.wrap && self.grid.is_line_full(coords, direction);
+		let push_chain_too_long = push_depth > self.grid.w.max(self.grid.h);
 		if let Some(tile) = self.grid.get(coords) {
 			if let Some(obj) = &tile.obj {
 				if obj.kind == ObjKind::Player {
-					if let Some(exit) = &tile.exit {
-						if direction == exit.direction {
-							let dst_level_id = exit.dst_level_id.clone();
-							self.go_to_level(&dst_level_id);
-							return;
-						}
+					if let Some(exit) = tile.exit(direction) {
+						self.taken_exit = Some(exit.clone());
+						return false;
+					}
+				} else if self.exiting_kinds.contains(&obj.kind) {
+					if let Some(exit) = tile.exit(direction) {
+						let exit = exit.clone();
+						let obj = self.grid.get_mut(coords).unwrap().obj.take().unwrap();
+						self.sent_objects.push((obj.kind, exit));
+						self.sound_effects.push(SoundEffect::Move);
+						return true;
 					}
 				}
-				if obj.can_move() {
+				let too_light_to_be_pushed =
+					obj.kind == ObjKind::Balloon && !matches!(pushed_by, Some(ObjKind::Player));
+				if pushing_full_ring || push_chain_too_long || too_light_to_be_pushed {
+					failed_to_move = true;
+				} else if obj.can_move() {
 					if let Some(tile_dst) = self.grid.get(coords_dst.into()) {
 						if let Some(obj_dst) = &tile_dst.obj {
 							if matches!(obj_dst.kind, ObjKind::Soap) {
@@ -983,14 +3589,35 @@ impl Game {
 							{
 								self.grid.get_mut(coords_dst.into()).unwrap().obj = None;
 								self.cheese_count_got_here += 1;
-							} else if matches!(obj.kind, ObjKind::Key)
-								&& matches!(obj_dst.kind, ObjKind::Door)
+								self.sound_effects.push(SoundEffect::Cheese);
+								self.cheese_eaten_at.push(coords_dst.into());
+							} else if matches!(obj.kind, ObjKind::Player)
+								&& matches!(obj_dst.kind, ObjKind::Fire { .. })
 							{
 								self.grid.get_mut(coords).unwrap().obj = None;
-								self.grid.get_mut(coords_dst.into()).unwrap().obj = None;
-								key_got_in_door = true;
+								self.player_died = true;
+								player_burnt = true;
+							} else if let (
+								ObjKind::Key { channel: key_channel },
+								ObjKind::Door { channel: door_channel },
+							) = (&obj.kind, &obj_dst.kind)
+							{
+								// A key only fits in doors of its channel, other doors block it.
+								if key_channel == door_channel {
+									self.grid.get_mut(coords).unwrap().obj = None;
+									self.grid.get_mut(coords_dst.into()).unwrap().obj = None;
+									key_got_in_door = true;
+									self.sound_effects.push(SoundEffect::Door);
+								}
 							} else {
-								self.obj_move(coords_dst.into(), direction, true);
+								self.obj_move_without_pulling(
+									coords_dst.into(),
+									direction,
+									Some(obj.kind.clone()),
+									push_depth + 1,
+								);
+								// The pushed object may have made room on ice to glide further.
+								coords_dst = self.slide_destination(coords, coords_dst, direction);
 							}
 						}
 					}
@@ -1003,7 +3630,12 @@ impl Game {
 						}
 					}
 					if let Some(tile_dst) = self.grid.get(coords_dst.into()) {
-						if tile_dst.obj.is_none() {
+						let can_enter = self
+							.grid
+							.get(coords)
+							.and_then(|tile| tile.obj.as_ref())
+							.is_none_or(|obj| tile_dst.ground.can_be_entered_by(&obj.kind));
+						if tile_dst.obj.is_none() && can_enter {
 							shall_move = true;
 						} else {
 							failed_to_move = true;
@@ -1015,339 +3647,2405 @@ impl Game {
 			}
 		}
 
-		let mut obj_is_rope = false;
-		if shall_move && !key_got_in_door {
-			let mut obj = self.grid.get_mut(coords).unwrap().obj.take();
-			obj.as_mut().unwrap().moved = true;
-			obj.as_mut().unwrap().animation = Animation::CommingFrom {
-				src: coords,
-				time_start: Instant::now(),
-				duration: Duration::from_secs_f32(0.05),
+		if shall_move && !key_got_in_door {
+			// When going through an edge of a wrapping grid, the object comes in from
+			// outside of the opposite edge.
+			let coords_dst_wrapped = IVec2::from(self.grid.wrapped(coords_dst.into()));
+			let mut obj = self.grid.get_mut(coords).unwrap().obj.take();
+			obj.as_mut().unwrap().moved = true;
+			obj.as_mut().unwrap().animation = Animation::CommingFrom {
+				src: match teleported_from {
+					Some(teleporter_coords) => teleporter_coords,
+					None => (coords_dst_wrapped - coords_dst + IVec2::from(coords)).into(),
+				},
+				time_start: Instant::now(),
+				duration: Duration::from_secs_f32(0.05),
+			};
+			self.grid.get_mut(coords_dst.into()).unwrap().obj = obj;
+			self.sound_effects.push(if pushed_by.is_some() {
+				SoundEffect::Push
+			} else {
+				SoundEffect::Move
+			});
+
+			if let Some(mut soap) = soap_getting_back.take() {
+				if matches!(soap.animation, Animation::None) {
+					soap.animation = Animation::CommingFrom {
+						src: coords_dst.into(),
+						time_start: Instant::now(),
+						duration: Duration::from_secs_f32(0.05),
+					};
+					soap.moved = true;
+				}
+				self.grid.get_mut(coords).unwrap().obj = Some(soap);
+			}
+
+			self.handle_sapling(false);
+			self.handle_pits();
+		} else if failed_to_move && !player_burnt {
+			self.fail_to_move(coords, coords_dst.into());
+		}
+
+		shall_move
+	}
+
+	/// The object at `coords` bumps toward `dst` without getting there.
+	fn fail_to_move(&mut self, coords: Point2<i32>, dst: Point2<i32>) {
+		self.sound_effects.push(SoundEffect::FailToMove);
+		if let Some(obj) = self.grid.get_mut(coords).unwrap().obj.as_mut() {
+			obj.animation = Animation::FailingToMoveTo {
+				dst,
+				time_start: Instant::now(),
+				duration: Duration::from_secs_f32(0.05),
+			};
+		}
+	}
+
+	/// Doors of channels that have an object on one of their plates are opened,
+	/// the other open doors close back unless something is in the way.
+	fn handle_plates(&mut self) {
+		let pressed_channels: HashSet<u8> = self
+			.grid
+			.tiles
+			.iter()
+			.filter_map(|tile| match tile.ground {
+				Ground::Plate { channel } if tile.obj.is_some() => Some(channel),
+				_ => None,
+			})
+			.collect();
+		let mut ground_changed = false;
+		for tile in self.grid.tiles.iter_mut() {
+			match (&tile.ground, &tile.obj) {
+				(_, Some(Obj { kind: ObjKind::Door { channel }, .. }))
+					if pressed_channels.contains(channel) =>
+				{
+					let under = Box::new(tile.ground.clone());
+					tile.ground = Ground::OpenDoor { channel: *channel, under };
+					tile.obj = None;
+					ground_changed = true;
+				},
+				(Ground::OpenDoor { channel, under }, None) if !pressed_channels.contains(channel) => {
+					tile.obj = Some(Obj::from_kind(ObjKind::Door { channel: *channel }));
+					tile.ground = (**under).clone();
+					ground_changed = true;
+				},
+				_ => {},
+			}
+		}
+		if ground_changed {
+			self.grid.ground_changed();
+		}
+	}
+
+	/// Where the object at `coords` moving onto `coords_dst` ends up, gliding over the free ice
+	/// tiles in its way until it would hit something or until it gets off the ice.
+	/// A ground it cannot get in (such as water for a player) stops it like an object would,
+	/// a ground it can get in ends the slide there (a rock sliding into water fills it).
+	fn slide_destination(&self, coords: Point2<i32>, coords_dst: IVec2, direction: IVec2) -> IVec2 {
+		let kind = self
+			.grid
+			.get(coords)
+			.and_then(|tile| tile.obj.as_ref())
+			.map(|obj| &obj.kind);
+		let is_free_ice = |coords: IVec2| {
+			self
+				.grid
+				.get(coords.into())
+				.is_some_and(|tile| tile.obj.is_none() && matches!(tile.ground, Ground::Ice))
+		};
+		let is_free = |coords: IVec2| {
+			self.grid.get(coords.into()).is_some_and(|tile| {
+				tile.obj.is_none() && kind.is_none_or(|kind| tile.ground.can_be_entered_by(kind))
+			})
+		};
+		let mut coords_dst = coords_dst;
+		// On a wrapping grid a line of ice could go on forever.
+		let max_slide_length = self.grid.w.max(self.grid.h);
+		let mut slide_length = 0;
+		while is_free_ice(coords_dst)
+			&& is_free(coords_dst + direction)
+			&& slide_length < max_slide_length
+		{
+			coords_dst += direction;
+			slide_length += 1;
+		}
+		coords_dst
+	}
+
+	/// The coords of a teleporter of the given channel that is not at the given coords.
+	fn other_teleporter_coords(&self, coords: Point2<i32>, channel: u8) -> Option<Point2<i32>> {
+		for grid_y in 0..self.grid.h {
+			for grid_x in 0..self.grid.w {
+				let other_coords = Point2::from([grid_x, grid_y]);
+				let is_teleporter_of_channel = self
+					.grid
+					.get(other_coords)
+					.unwrap()
+					.obj
+					.as_ref()
+					.is_some_and(|obj| obj.kind == ObjKind::Teleporter { channel });
+				if is_teleporter_of_channel && other_coords != coords {
+					return Some(other_coords);
+				}
+			}
+		}
+		None
+	}
+
+	/// Objects on conveyors are moved by one tile in the conveyor's direction if there is room.
+	/// Conveyors are handled in reading order, so when two conveyors lead to the same free tile
+	/// the first one gets its object there and the other one is blocked.
+	fn handle_conveyors(&mut self) {
+		let mut objects_on_conveyors = vec![];
+		for grid_y in 0..self.grid.h {
+			for grid_x in 0..self.grid.w {
+				let coords = Point2::from([grid_x, grid_y]);
+				let tile = self.grid.get(coords).unwrap();
+				if let Ground::Conveyor { direction } = tile.ground {
+					if let Some(obj) = tile.obj.as_ref().filter(|obj| obj.can_move()) {
+						objects_on_conveyors.push((coords, direction, obj.kind.clone()));
+					}
+				}
+			}
+		}
+		// Only the objects that were on conveyors before any of them moved are moved,
+		// an object moved onto another conveyor waits for the next step.
+		for (coords, direction, kind) in objects_on_conveyors {
+			let coords_dst = self.grid.wrapped((IVec2::from(coords) + direction).into());
+			if self
+				.grid
+				.get(coords_dst)
+				.is_some_and(|tile| tile.obj.is_none() && tile.ground.can_be_entered_by(&kind))
+			{
+				let mut obj = self.grid.get_mut(coords).unwrap().obj.take();
+				obj.as_mut().unwrap().moved = true;
+				obj.as_mut().unwrap().animation = Animation::CommingFrom {
+					src: (IVec2::from(coords_dst) - direction).into(),
+					time_start: Instant::now(),
+					duration: Duration::from_secs_f32(0.05),
+				};
+				self.grid.get_mut(coords_dst).unwrap().obj = obj;
+			}
+		}
+	}
+
+	fn first_player_coords(&self) -> Option<Point2<i32>> {
+		for grid_y in 0..self.grid.h {
+			for grid_x in 0..self.grid.w {
+				let coords = Point2::from([grid_x, grid_y]);
+				let tile = self.grid.get(coords).unwrap();
+				if tile
+					.obj
+					.as_ref()
+					.is_some_and(|obj| obj.kind == ObjKind::Player)
+				{
+					return Some(coords);
+				}
+			}
+		}
+		None
+	}
+
+	/// Every goal tile is covered by an object of the given kind.
+	/// A level without goal tiles does not count as having all its goals covered.
+	fn all_goals_covered(&self, kind: &ObjKind) -> bool {
+		let mut goal_tiles = self
+			.grid
+			.tiles
+			.iter()
+			.filter(|tile| matches!(tile.ground, Ground::Goal))
+			.peekable();
+		goal_tiles.peek().is_some()
+			&& goal_tiles.all(|tile| tile.obj.as_ref().is_some_and(|obj| &obj.kind == kind))
+	}
+
+	/// Hash of what is where on the grid, to tell apart states that matter.
+	fn layout_hash(&self) -> u64 {
+		let mut hasher = FnvHasher::default();
+		self.grid.state_hash().hash(&mut hasher);
+		if !self.omnidirectional {
+			self.facing.to_array().hash(&mut hasher);
+		}
+		for (kind, _exit) in self.sent_objects.iter() {
+			kind.hash(&mut hasher);
+		}
+		hasher.finish()
+	}
+
+	/// The step limit of the level is reached, the player cannot move anymore
+	/// (but it can still shoot).
+	fn is_out_of_steps(&self) -> bool {
+		self
+			.step_limit
+			.is_some_and(|step_limit| self.step_count >= step_limit)
+	}
+
+	fn is_won(&self) -> bool {
+		match &self.win_condition {
+			None => false,
+			// Cheese sent away through an exit is not collected.
+			Some(WinCondition::CollectAllCheese) => {
+				!self.grid.tiles.iter().any(|tile| {
+					tile
+						.obj
+						.as_ref()
+						.is_some_and(|obj| obj.kind == ObjKind::Cheese)
+				}) && !self
+					.sent_objects
+					.iter()
+					.any(|(kind, _exit)| *kind == ObjKind::Cheese)
+			},
+			Some(WinCondition::CoverAllGoals { kind }) => self.all_goals_covered(kind),
+		}
+	}
+
+	/// All the players make the step at once (see `move_together`).
+	fn player_move(&mut self, direction: IVec2) -> MoveOutcome {
+		let state_hash_before = self.grid.state_hash();
+		let facing_before = self.facing;
+		let cheese_count_before = self.cheese_count_got_here;
+		self.facing = direction;
+		self.clear_processed_flags();
+		self.clear_moved_flags();
+		self.clear_animations();
+
+		let mut moves = vec![];
+		for coords in self.tick_order_coords(direction) {
+			if let Some(obj) = &self.grid.get(coords).unwrap().obj {
+				if obj.kind == ObjKind::Player {
+					moves.push((coords, direction));
+				}
+			}
+		}
+		self.move_together(moves, |obj| {
+			obj.kind == ObjKind::Player && !obj.processed && !obj.moved
+		});
+
+		self.step_count += 1;
+		self.handle_spikes();
+		self.handle_crumbling_floors();
+		// Before the saplings grow, a tree grown this step does not catch fire right away.
+		self.handle_fire();
+		self.handle_sapling(true);
+		self.handle_bunnies();
+		self.handle_sapling(true);
+		self.handle_hunters();
+		self.handle_sapling(true);
+		self.handle_magnets();
+		self.handle_sapling(true);
+		self.handle_conveyors();
+		self.handle_pits();
+		self.handle_spikes();
+		self.handle_crumbling_floors();
+		self.handle_sapling(true);
+		self.handle_plates();
+
+		let mut moved = vec![];
+		let mut some_move_failed = false;
+		for grid_y in 0..self.grid.h {
+			for grid_x in 0..self.grid.w {
+				let coords = Point2::from([grid_x, grid_y]);
+				if let Some(obj) = &self.grid.get(coords).unwrap().obj {
+					match obj.animation {
+						Animation::CommingFrom { src, .. } if obj.moved => {
+							moved.push((obj.kind.clone(), self.grid.wrapped(src), coords));
+						},
+						Animation::FailingToMoveTo { .. } => some_move_failed = true,
+						_ => {},
+					}
+				}
+			}
+		}
+		let board_changed = (facing_before != self.facing && !self.omnidirectional)
+			|| state_hash_before != self.grid.state_hash();
+		MoveOutcome {
+			moved,
+			exited_to: self.taken_exit.take(),
+			cheese_gained: self.cheese_count_got_here - cheese_count_before,
+			some_move_failed,
+			board_changed,
+		}
+	}
+
+	fn player_shoot(&mut self) {
+		self.clear_processed_flags();
+		self.clear_moved_flags();
+		self.clear_animations();
+
+		for grid_y in 0..self.grid.h {
+			for grid_x in 0..self.grid.w {
+				let coords = Point2::from([grid_x, grid_y]);
+				if let Some(obj) = &self.grid.get(coords).unwrap().obj {
+					if matches!(obj.kind, ObjKind::Player) && !obj.processed {
+						self
+							.grid
+							.get_mut(coords)
+							.unwrap()
+							.obj
+							.as_mut()
+							.unwrap()
+							.processed = true;
+						for player_to_neighboor in self.shooting_directions() {
+							let neighboor_coords = IVec2::from(coords) + player_to_neighboor;
+							if let Some(neighboor_obj) = &self
+								.grid
+								.get(neighboor_coords.into())
+								.and_then(|tile| tile.obj.as_ref())
+							{
+								if let ObjKind::Raygun(kind) = neighboor_obj.kind.clone() {
+									self.sound_effects.push(SoundEffect::Shoot);
+									self.rays.push(Ray {
+										coords: neighboor_coords.into(),
+										direction: player_to_neighboor,
+										action: RayAction::from_raygun_kind(kind, coords),
+										step_count: 0,
+										path: vec![],
+									})
+								}
+							}
+						}
+					}
+				}
+			}
+		}
+	}
+
+	/// The sides of the players where the rayguns they would shoot are.
+	fn shooting_directions(&self) -> Vec<IVec2> {
+		if self.omnidirectional {
+			vec![(1, 0).into(), (0, 1).into(), (-1, 0).into(), (0, -1).into()]
+		} else {
+			vec![self.facing]
+		}
+	}
+
+	/// Coords of the rayguns that would fire if the player shot now.
+	fn rayguns_in_reach(&self) -> Vec<Point2<i32>> {
+		let mut rayguns = vec![];
+		for grid_y in 0..self.grid.h {
+			for grid_x in 0..self.grid.w {
+				let coords = Point2::from([grid_x, grid_y]);
+				if !self
+					.grid
+					.get(coords)
+					.unwrap()
+					.obj
+					.as_ref()
+					.is_some_and(|obj| obj.kind == ObjKind::Player)
+				{
+					continue;
+				}
+				for player_to_neighboor in self.shooting_directions() {
+					let neighboor_coords = self
+						.grid
+						.wrapped((IVec2::from(coords) + player_to_neighboor).into());
+					if self
+						.grid
+						.get(neighboor_coords)
+						.and_then(|tile| tile.obj.as_ref())
+						.is_some_and(|obj| matches!(obj.kind, ObjKind::Raygun(_)))
+					{
+						rayguns.push(neighboor_coords);
+					}
+				}
+			}
+		}
+		rayguns
+	}
+
+	/// Nothing is going on that the player has to wait for (rays flying or bombs about to blow).
+	fn is_settled(&self) -> bool {
+		self.rays.is_empty() && self.lit_bombs.is_empty()
+	}
+
+	/// Makes all the lit bombs explode at once. Objects in the 8 tiles around a bomb
+	/// are destroyed (players die), except walls that resist and bombs that are lit instead.
+	fn detonate_bombs(&mut self) {
+		let lit_bombs = std::mem::take(&mut self.lit_bombs);
+		for center in lit_bombs.iter().copied() {
+			// A bomb may have been lit and then pushed or destroyed before blowing.
+			let tile = self.grid.get_mut(center).unwrap();
+			if !tile
+				.obj
+				.as_ref()
+				.is_some_and(|obj| obj.kind == ObjKind::Bomb)
+			{
+				continue;
+			}
+			tile.obj = None;
+			self.sound_effects.push(SoundEffect::Explosion);
+			for dy in -1..=1 {
+				for dx in -1..=1 {
+					let coords = self
+						.grid
+						.wrapped((IVec2::from(center) + IVec2::new(dx, dy)).into());
+					let Some(tile) = self.grid.get_mut(coords) else {
+						continue;
+					};
+					match tile.obj.as_ref().map(|obj| &obj.kind) {
+						None | Some(ObjKind::Wall | ObjKind::WallWithHoles) => {},
+						Some(ObjKind::Bomb) => {
+							if !lit_bombs.contains(&coords) && !self.lit_bombs.contains(&coords) {
+								self.lit_bombs.push(coords);
+							}
+						},
+						Some(ObjKind::Player) => {
+							tile.obj = None;
+							self.player_died = true;
+						},
+						Some(_) => tile.obj = None,
+					}
+				}
+			}
+		}
+		self.handle_crumbling_floors();
+		self.handle_sapling(true);
+		self.handle_plates();
+	}
+
+	/// Makes every ray advance by one tile, resolving the rays that hit something.
+	/// Rays are resolved one after the other in the order they were shot,
+	/// each one seeing the grid as left by the previous ones.
+	fn advance_rays(&mut self) {
+		let mut rays_indices_to_remove = vec![];
+		// Tiles whose object was changed by a ray resolved earlier in this advance.
+		let mut affected_coords: Vec<Point2<i32>> = vec![];
+		// Pushes are done after going through the rays as they can move more than the shootee.
+		let mut pushes = vec![];
+		let mut triggered_rays = vec![];
+		let ray_count = self.rays.len();
+		for (ray_index, ray) in self.rays.iter_mut().enumerate() {
+			ray.step_count += 1;
+			if ray.step_count > Ray::MAX_STEP_COUNT {
+				rays_indices_to_remove.push(ray_index);
+				if matches!(ray.action, RayAction::ThrowAxe) {
+					affected_coords.extend(ray.land_axe(&mut self.grid));
+				}
+				continue;
+			}
+			ray.path.push(ray.coords);
+			let dst_coords = IVec2::from(
+				self
+					.grid
+					.wrapped((IVec2::from(ray.coords) + ray.direction).into()),
+			);
+			if let Some(dst_tile) = self.grid.get(dst_coords.into()) {
+				if matches!(ray.action, RayAction::ThrowAxe) && dst_tile.obj.is_some() {
+					rays_indices_to_remove.push(ray_index);
+					let hits_tree = dst_tile
+						.obj
+						.as_ref()
+						.is_some_and(|obj| obj.kind == ObjKind::Tree);
+					if hits_tree {
+						self.grid.get_mut(dst_coords.into()).unwrap().obj = None;
+						affected_coords.push(dst_coords.into());
+					}
+					affected_coords.extend(ray.land_axe(&mut self.grid));
+				} else if matches!(ray.action, RayAction::Push)
+					&& dst_tile
+						.obj
+						.as_ref()
+						.is_some_and(|obj| obj.kind == ObjKind::Axe)
+				{
+					// The axe is thrown rather than pushed, it flies carried by a ray of its own.
+					rays_indices_to_remove.push(ray_index);
+					self.grid.get_mut(dst_coords.into()).unwrap().obj = None;
+					affected_coords.push(dst_coords.into());
+					triggered_rays.push(Ray {
+						coords: dst_coords.into(),
+						direction: ray.direction,
+						action: RayAction::ThrowAxe,
+						step_count: ray.step_count,
+						path: vec![],
+					});
+				} else if dst_tile
+					.obj
+					.as_ref()
+					.is_some_and(|obj| matches!(obj.kind, ObjKind::WallWithHoles))
+				{
+					ray.coords = dst_coords.into();
+				} else if dst_tile
+					.obj
+					.as_ref()
+					.is_some_and(|obj| matches!(obj.kind, ObjKind::Mirror))
+				{
+					ray.coords = dst_coords.into();
+					ray.direction = -ray.direction;
+				} else if dst_tile
+					.obj
+					.as_ref()
+					.is_some_and(|obj| matches!(obj.kind, ObjKind::MirrorSlopeUp))
+				{
+					ray.coords = dst_coords.into();
+					let dir = ray.direction;
+					ray.direction.y = -dir.x;
+					ray.direction.x = -dir.y;
+				} else if dst_tile
+					.obj
+					.as_ref()
+					.is_some_and(|obj| matches!(obj.kind, ObjKind::MirrorSlopeDown))
+				{
+					ray.coords = dst_coords.into();
+					let dir = ray.direction;
+					ray.direction.y = dir.x;
+					ray.direction.x = dir.y;
+				} else if let Some(ObjKind::Glass { turn }) =
+					dst_tile.obj.as_ref().map(|obj| obj.kind.clone())
+				{
+					ray.coords = dst_coords.into();
+					ray.direction = turn.apply(ray.direction);
+				} else if dst_tile
+					.obj
+					.as_ref()
+					.is_some_and(|obj| matches!(obj.kind, ObjKind::Prism))
+				{
+					// The split rays go on from the prism, keeping the step count and the beam
+					// of the ray that they come from.
+					rays_indices_to_remove.push(ray_index);
+					if ray_count + triggered_rays.len() < Ray::MAX_COUNT {
+						for turn in [Turn::Left, Turn::Right] {
+							triggered_rays.push(Ray {
+								coords: dst_coords.into(),
+								direction: turn.apply(ray.direction),
+								action: ray.action.clone(),
+								step_count: ray.step_count,
+								path: ray.path.clone(),
+							});
+						}
+					}
+				} else if let Some(ObjKind::Raygun(kind)) =
+					dst_tile.obj.as_ref().map(|obj| obj.kind.clone())
+				{
+					// A raygun hit by a ray shoots in turn, being its own shooter. The new ray keeps
+					// the step count of the one that triggered it so that chains cannot go on forever.
+					rays_indices_to_remove.push(ray_index);
+					triggered_rays.push(Ray {
+						coords: dst_coords.into(),
+						direction: ray.direction,
+						action: RayAction::from_raygun_kind(kind, dst_coords.into()),
+						step_count: ray.step_count,
+						path: vec![],
+					});
+				} else if dst_tile
+					.obj
+					.as_ref()
+					.is_some_and(|obj| matches!(obj.kind, ObjKind::Bomb))
+				{
+					rays_indices_to_remove.push(ray_index);
+					if !self.lit_bombs.contains(&dst_coords.into()) {
+						self.lit_bombs.push(dst_coords.into());
+					}
+				} else if dst_tile.obj.is_some() {
+					match ray.action {
+						RayAction::SwapWith { with_who_coords } => {
+							rays_indices_to_remove.push(ray_index);
+							// If the shooter was already swapped away or otherwise consumed
+							// by an earlier ray then there is no one to swap with anymore.
+							let shooter_is_gone = affected_coords.contains(&with_who_coords)
+								|| self
+									.grid
+									.get(with_who_coords)
+									.is_none_or(|tile| tile.obj.is_none());
+							if !shooter_is_gone {
+								let shootee = self.grid.get_mut(dst_coords.into()).unwrap().obj.take();
+								let shooter = self.grid.get_mut(with_who_coords).unwrap().obj.take();
+								self.grid.get_mut(dst_coords.into()).unwrap().obj = shooter;
+								self.grid.get_mut(with_who_coords).unwrap().obj = shootee;
+								affected_coords.push(with_who_coords);
+								affected_coords.push(dst_coords.into());
+							}
+						},
+						RayAction::Duplicate => {
+							rays_indices_to_remove.push(ray_index);
+							// An earlier ray may have removed the shootee, then there is nothing to duplicate.
+							let shootee_kind = self
+								.grid
+								.get(dst_coords.into())
+								.and_then(|tile| tile.obj.as_ref())
+								.map(|obj| obj.kind.clone());
+							if let Some(shootee_kind) = shootee_kind {
+								let obj_to_be_duplicated_to =
+									&mut self.grid.get_mut(ray.coords).unwrap().obj;
+								if obj_to_be_duplicated_to.is_none() {
+									*obj_to_be_duplicated_to = Some(Obj::from_kind(shootee_kind));
+									affected_coords.push(ray.coords);
+								}
+							}
+						},
+						RayAction::TurnInto { ref into_what } => {
+							rays_indices_to_remove.push(ray_index);
+							self.grid.get_mut(dst_coords.into()).unwrap().obj =
+								Some(Obj::from_kind(into_what.clone()));
+							affected_coords.push(dst_coords.into());
+						},
+						RayAction::TurnIntoTurnInto => {
+							rays_indices_to_remove.push(ray_index);
+							let shootee = self
+								.grid
+								.get_mut(dst_coords.into())
+								.unwrap()
+								.obj
+								.take()
+								.unwrap();
+							self.grid.get_mut(dst_coords.into()).unwrap().obj = Some(Obj::from_kind(
+								ObjKind::Raygun(RaygunKind::TurnInto(Box::new(shootee.kind))),
+							));
+							affected_coords.push(dst_coords.into());
+						},
+						RayAction::Delete => {
+							rays_indices_to_remove.push(ray_index);
+							self.grid.get_mut(dst_coords.into()).unwrap().obj.take();
+							affected_coords.push(dst_coords.into());
+						},
+						RayAction::Push => {
+							rays_indices_to_remove.push(ray_index);
+							if self
+								.grid
+								.get((dst_coords + ray.direction).into())
+								.is_some_and(|tile| tile.obj.is_none())
+							{
+								pushes.push((dst_coords, ray.direction));
+							}
+						},
+						// A thrown axe stops at any object, that is handled first.
+						RayAction::ThrowAxe => unreachable!(),
+					}
+				} else {
+					ray.coords = dst_coords.into();
+				}
+			} else {
+				rays_indices_to_remove.push(ray_index);
+				if matches!(ray.action, RayAction::ThrowAxe) {
+					affected_coords.extend(ray.land_axe(&mut self.grid));
+				}
+			}
+		}
+		rays_indices_to_remove.sort();
+		for index_to_remove in rays_indices_to_remove.into_iter().rev() {
+			self.rays.remove(index_to_remove);
+		}
+		self.rays.extend(triggered_rays);
+		for (coords, direction) in pushes {
+			self.obj_move(
+				coords.into(),
+				direction,
+				Some(ObjKind::Raygun(RaygunKind::Push)),
+			);
+		}
+		self.handle_pits();
+		self.handle_spikes();
+		self.handle_crumbling_floors();
+		self.handle_sapling(true);
+		self.handle_plates();
+	}
+
+	/// Advances the rays and explosions without waiting for their animations,
+	/// returns false if they do not settle (rays bouncing between mirrors could go on forever).
+	fn settle(&mut self) -> bool {
+		let max_ray_steps = (self.grid.w * self.grid.h * 4) as usize;
+		let mut ray_steps = 0;
+		while !self.is_settled() && ray_steps < max_ray_steps {
+			if self.rays.is_empty() {
+				self.detonate_bombs();
+			} else {
+				self.advance_rays();
+			}
+			ray_steps += 1;
+		}
+		self.is_settled()
+	}
+}
+
+/// Breadth-first search over the player's moves and shots for a way to complete the level
+/// (meet its win condition, or take an exit if it has none).
+/// Returns the number of moves of a shortest solution.
+fn solve(level: &Level) -> Result<u32, String> {
+	const MAX_DEPTH: u32 = 200;
+	const MAX_VISITED_STATES: usize = 100_000;
+	if level.win_condition.is_none() && !level.grid.tiles.iter().any(|tile| !tile.exits.is_empty()) {
+		return Err("has no win condition nor exit".to_string());
+	}
+	let start = Simulation::new(level);
+	let mut visited = HashSet::from([start.layout_hash()]);
+	let mut queue = VecDeque::from([(start, 0)]);
+	while let Some((sim, depth)) = queue.pop_front() {
+		if depth >= MAX_DEPTH {
+			continue;
+		}
+		let directions =
+			[(1, 0), (0, 1), (-1, 0), (0, -1)].map(|(dx, dy)| Some(IVec2::from([dx, dy])));
+		for action in directions.into_iter().chain([None]) {
+			if action.is_some() && sim.is_out_of_steps() {
+				continue;
+			}
+			let mut next_sim = sim.clone();
+			let exited = match action {
+				Some(direction) => next_sim.player_move(direction).exited_to.is_some(),
+				None => {
+					next_sim.player_shoot();
+					if !next_sim.settle() {
+						continue;
+					}
+					false
+				},
+			};
+			let completed = if level.win_condition.is_some() {
+				next_sim.is_won()
+			} else {
+				exited
+			};
+			if completed {
+				return Ok(depth + 1);
+			}
+			if exited || next_sim.player_died || !visited.insert(next_sim.layout_hash()) {
+				continue;
+			}
+			if visited.len() >= MAX_VISITED_STATES {
+				return Err(format!(
+					"has no solution found before the search limit of {MAX_VISITED_STATES} states"
+				));
+			}
+			queue.push_back((next_sim, depth + 1));
+		}
+	}
+	Err(format!("cannot be completed in {MAX_DEPTH} moves or less"))
+}
+
+/// A level, or the level file to parse it from when it is first needed.
+enum LevelEntry {
+	Parsed(Rc<Level>),
+	Unparsed(PathBuf),
+}
+
+/// The test level and the levels in the levels directory, by id.
+/// Level files are only parsed when their level is first needed, only their ids
+/// are looked for when they are listed (so that duplicate ids are reported right away).
+struct Levels {
+	entries: HashMap<String, LevelEntry>,
+	/// Problems with level files (such as files that could not be read), also printed.
+	warnings: Vec<String>,
+}
+
+impl Levels {
+	/// Lists the level files (and parses them all right away if `preload` is set).
+	fn load(preload: bool) -> Levels {
+		let mut levels = Levels { entries: HashMap::new(), warnings: vec![] };
+		let mut listed_levels: Vec<(String, PathBuf)> = vec![];
+		match std::fs::read_dir("levels") {
+			Ok(level_files) => {
+				for level_file in level_files {
+					let level_file = match level_file {
+						Ok(level_file) => level_file,
+						Err(error) => {
+							levels.warn(format!("warning: failed to list a level file: {error}"));
+							continue;
+						},
+					};
+					let level_path = level_file.path();
+					let file_stem = level_path.file_stem().unwrap_or_default().to_string_lossy();
+					// A file that cannot be read is reported when it is parsed.
+					let id = Level::id_in_file(&level_path).unwrap_or_else(|| file_stem.to_string());
+					listed_levels.push((id, level_path));
+				}
+			},
+			Err(error) => {
+				levels.warn(format!(
+					"warning: failed to read the levels directory: {error}"
+				));
+			},
+		}
+		// Among level files with the same id, a level file named after the id comes first.
+		let is_named_after_id =
+			|id: &str, path: &Path| path.file_stem().is_some_and(|stem| stem == id);
+		listed_levels
+			.sort_by_key(|(id, path)| (id.clone(), !is_named_after_id(id, path), path.clone()));
+		for (id, level_path) in listed_levels {
+			if let Some(LevelEntry::Unparsed(other_path)) = levels.entries.get(&id) {
+				let warning = format!(
+					"warning: level file {} and level file {} both have the id \"{id}\", \
+					ignoring the latter",
+					other_path.display(),
+					level_path.display()
+				);
+				levels.warn(warning);
+				continue;
+			}
+			levels.entries.insert(id, LevelEntry::Unparsed(level_path));
+		}
+		// Inserted after the level files are listed so that its exits can be checked.
+		levels.insert(Level::test());
+		if preload {
+			levels.parse_all();
+		}
+		levels
+	}
+
+	fn warn(&mut self, warning: String) {
+		println!("{warning}");
+		self.warnings.push(warning);
+	}
+
+	fn unparsed_keys(&self) -> Vec<String> {
+		let mut keys: Vec<String> = self
+			.entries
+			.iter()
+			.filter(|(_key, entry)| matches!(entry, LevelEntry::Unparsed(_)))
+			.map(|(key, _entry)| key.clone())
+			.collect();
+		// Sorted so that which of two levels with the same id is kept does not depend on luck.
+		keys.sort();
+		keys
+	}
+
+	/// Parses the level file of the given entry, the level is then filed under its actual id.
+	fn parse_entry(&mut self, key: &str) {
+		let Some(LevelEntry::Unparsed(level_path)) = self.entries.remove(key) else {
+			return;
+		};
+		match Level::load_from_file(&level_path) {
+			Ok(level) => {
+				self.insert(level);
+			},
+			Err(error) => self.warn(format!("warning: skipped {error}")),
+		}
+	}
+
+	/// Parses all the level files not parsed yet, to know all the level ids.
+	fn parse_all(&mut self) {
+		for key in self.unparsed_keys() {
+			self.parse_entry(&key);
+		}
+	}
+
+	/// Exits that lead to no level are reported as errors of the level they are in,
+	/// rather than failing when taken.
+	fn check_exits(&mut self, level: &mut Level) {
+		let mut dst_level_ids: Vec<String> = level
+			.grid
+			.tiles
+			.iter()
+			.flat_map(|tile| tile.exits.iter().map(|exit| exit.dst_level_id.clone()))
+			.collect();
+		dst_level_ids.sort();
+		dst_level_ids.dedup();
+		for dst_level_id in dst_level_ids {
+			// A level file can set an id that is not its file name, so not finding the id
+			// among the known ones requires to look into all the level files to be sure.
+			if !self.entries.contains_key(&dst_level_id) && dst_level_id != level.id {
+				self.parse_all();
+			}
+			if !self.entries.contains_key(&dst_level_id) && dst_level_id != level.id {
+				level.error_messages.push(format!(
+					"structural error: exit to level \"{dst_level_id}\" that does not exist"
+				));
+			}
+		}
+	}
+
+	/// Files the level under its id and returns it.
+	/// If another level file already has this id then that other level is kept (and returned)
+	/// with a warning, a level file named after the id coming first. A level coming from
+	/// the same file as the one already there replaces it (that is how levels are reloaded).
+	fn insert(&mut self, mut level: Level) -> Rc<Level> {
+		// Checking the exits can parse other level files, and one of them can have this id.
+		self.check_exits(&mut level);
+		if let Some(LevelEntry::Unparsed(level_path)) = self.entries.get(&level.id) {
+			if level.path.as_ref() != Some(level_path) {
+				let key = level.id.clone();
+				self.parse_entry(&key);
+			}
+		}
+		if let Some(LevelEntry::Parsed(other_level)) = self.entries.get(&level.id) {
+			if other_level.path.is_none() || other_level.path != level.path {
+				let other_level = Rc::clone(other_level);
+				let describe = |level: &Level| match &level.path {
+					Some(path) => format!("level file {}", path.display()),
+					None => "the built-in level".to_string(),
+				};
+				self.warn(format!(
+					"warning: {} and {} both have the id \"{}\", ignoring the latter",
+					describe(&other_level),
+					describe(&level),
+					level.id
+				));
+				return other_level;
+			}
+		}
+		let level = Rc::new(level);
+		self
+			.entries
+			.insert(level.id.clone(), LevelEntry::Parsed(Rc::clone(&level)));
+		level
+	}
+
+	/// Parses a level file from anywhere, the level takes precedence over any other level
+	/// with the same id (so that exits leading to its id lead to it).
+	fn insert_from_file(&mut self, path: &Path) -> Result<Rc<Level>, String> {
+		let level = Level::load_from_file(path)?;
+		self.entries.remove(&level.id);
+		Ok(self.insert(level))
+	}
+
+	/// All the levels, sorted by id, parsing the level files not parsed yet.
+	fn all(&mut self) -> Vec<Rc<Level>> {
+		self.parse_all();
+		let mut levels: Vec<Rc<Level>> = self
+			.entries
+			.values()
+			.filter_map(|entry| match entry {
+				LevelEntry::Parsed(level) => Some(Rc::clone(level)),
+				LevelEntry::Unparsed(_) => None,
+			})
+			.collect();
+		levels.sort_by(|level_a, level_b| level_a.id.cmp(&level_b.id));
+		levels
+	}
+
+	fn get(&mut self, level_id: &str) -> Result<Rc<Level>, String> {
+		self.parse_entry(level_id);
+		if let Some(LevelEntry::Parsed(level)) = self.entries.get(level_id) {
+			return Ok(Rc::clone(level));
+		}
+		// The level may be in a file named differently, they all have to be looked at.
+		for key in self.unparsed_keys() {
+			self.parse_entry(&key);
+			if let Some(LevelEntry::Parsed(level)) = self.entries.get(level_id) {
+				return Ok(Rc::clone(level));
+			}
+		}
+		Err(format!("there is no level with id \"{level_id}\""))
+	}
+}
+
+/// What the game shows and what the keys do.
+enum GameState {
+	/// Choosing a level to play among all the levels (listed when the menu is opened).
+	Menu {
+		levels: Vec<Rc<Level>>,
+		selected: usize,
+	},
+	Playing,
+	/// The level is frozen under a menu, quitting (which loses the progress in the level)
+	/// has to be confirmed.
+	Paused {
+		selected: PauseOption,
+		confirming_quit: bool,
+	},
+	/// The player died, the level is frozen for a moment before the attempt restarts.
+	Dying {
+		time_start: Instant,
+	},
+	/// The player took an exit, the view fades to black and back,
+	/// the exit is taken (and the level changes) in the middle of it.
+	LevelTransition {
+		exit: Option<Exit>,
+		time_start: Instant,
+	},
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PauseOption {
+	Resume,
+	Reset,
+	QuitToMenu,
+	QuitGame,
+}
+
+impl PauseOption {
+	const ALL: [PauseOption; 4] = [
+		PauseOption::Resume,
+		PauseOption::Reset,
+		PauseOption::QuitToMenu,
+		PauseOption::QuitGame,
+	];
+
+	fn text(self) -> &'static str {
+		match self {
+			PauseOption::Resume => "Resume",
+			PauseOption::Reset => "Reset",
+			PauseOption::QuitToMenu => "Quit to menu",
+			PauseOption::QuitGame => "Quit game",
+		}
+	}
+}
+
+struct Game {
+	state: GameState,
+	/// Levels are shared with `level` rather than cloned, only the grid is cloned
+	/// (by `Simulation::new`) when a level is (re)started.
+	levels: Levels,
+	level: Rc<Level>,
+	/// The named entry the player came in the level through, where resets bring it back.
+	entry_name: Option<String>,
+	/// Objects sent through exits to each level (by id), with the name of their entry.
+	/// They are there when the level is entered, until the player leaves it.
+	delivered_objects: HashMap<String, Vec<(ObjKind, Option<String>)>>,
+	sim: Simulation,
+	notes: Vec<Note>,
+	/// The page of notes currently shown.
+	note_page: u32,
+	rays_animation: Option<RaysAnimation>,
+	/// When the lit bombs started waiting to explode.
+	fuse_start: Option<Instant>,
+	blasts: Vec<Blast>,
+	/// Tells what to do in the level, shown for a few seconds when entering a level.
+	objective_banner: Option<Banner>,
+	/// The frame has to be drawn again, it is not the case when nothing changed since the last one.
+	dirty: bool,
+	/// Recent positions of the player, oldest first, drawn as a trail if `show_trail` is set.
+	trail: VecDeque<Point2<i32>>,
+	show_trail: bool,
+	screen_shake: bool,
+	shake: Option<Shake>,
+	particles: Vec<Particle>,
+	/// The statistics panel is shown over the board.
+	show_stats: bool,
+	/// What is in the tile under the cursor is shown, for debugging levels.
+	show_debug: bool,
+	/// What the last player move did, shown along with the tile under the cursor.
+	last_move_outcome: Option<MoveOutcome>,
+	/// Where the cursor is in the window.
+	mouse_position: Vec2,
+	/// The player sprite is flipped to face the way the player last moved horizontally.
+	player_facing_left: bool,
+	/// Lines between the tiles and their coords are drawn over the board.
+	show_grid_lines: bool,
+	/// What the player did since the start of the attempt, saved as a replay if it succeeds.
+	inputs: Vec<Input>,
+	/// Inputs of a replay being played back, the next one is played as soon as possible.
+	replay_inputs: VecDeque<Input>,
+	/// Retraces the best solution of the level, if there is one.
+	ghost: Option<Ghost>,
+	recording: Option<Recording>,
+	undo_stack: Vec<Snapshot>,
+	/// Snapshots popped by undo, cleared as soon as the player does something new.
+	redo_stack: Vec<Snapshot>,
+	spritesheet: Image,
+	/// Cheese brought out of the levels through exits,
+	/// it does not include the provisional `sim.cheese_count_got_here`.
+	cheese_count: u32,
+	/// Steps taken in the previous levels, counting only the attempts that took an exit.
+	step_count_at_level_start: u32,
+	reset_count: u32,
+	death_count: u32,
+	/// The win condition of the level is met, the player cannot move anymore.
+	won: bool,
+	/// Best step count of each completed level, by level id, persisted to disk.
+	best_steps: HashMap<String, u32>,
+	/// Problems not specific to the current level (such as a missing level),
+	/// shown along with the warnings of `levels`.
+	warnings: Vec<String>,
+	/// Problems met by the last reload of the current level, shown until a reload succeeds.
+	reload_warnings: Vec<String>,
+	/// Number of tiles the window is currently sized to show, so that it can follow the level's size
+	/// (up to a maximum, the camera scrolls over larger grids).
+	window_grid_size: (i32, i32),
+	/// Size of the window in pixels, the view is scaled to fit it.
+	window_size: Vec2,
+	fullscreen: bool,
+	/// Sound of each sound effect, emptied if playing a sound fails so that the game
+	/// goes on in silence.
+	sounds: HashMap<SoundEffect, audio::SoundData>,
+	/// Looping background music, if there is a music file.
+	music: Option<audio::Source>,
+	audio_settings: AudioSettings,
+	/// Directions pressed while the player could not move yet (rays or moves still animating),
+	/// played in order as soon as possible.
+	input_queue: VecDeque<IVec2>,
+	keybindings: Keybindings,
+	/// Built when first drawn and then only when the ground changes.
+	ground_layer: Option<GroundLayer>,
+	/// Sprites of the objects, filled again for every frame.
+	object_instances: graphics::InstanceArray,
+}
+
+#[derive(Parser)]
+#[command(color = clap::ColorChoice::Auto)]
+struct CommandLineSettings {
+	/// Level to start in, either its id or the path to a level file
+	/// (that does not have to be in the levels directory).
+	#[arg(long = "level", short = 'l', value_name = "LEVEL")]
+	level_id: Option<String>,
+	/// Draw a fading trail behind the player.
+	#[arg(long = "trail")]
+	trail: bool,
+	/// Do not shake the view when bumping into things or when bombs explode.
+	#[arg(long = "no-screen-shake")]
+	no_screen_shake: bool,
+	/// Instead of playing, search for a solution to the given level and tell if there is one.
+	#[arg(long = "check", value_name = "LEVEL_ID")]
+	check_level_id: Option<String>,
+	/// Instead of playing, print the given level in the level file format.
+	#[arg(long = "export", value_name = "LEVEL_ID")]
+	export_level_id: Option<String>,
+	/// Read all the level files at startup instead of when their levels are first needed.
+	#[arg(long = "preload")]
+	preload: bool,
+	/// Instead of playing, check all the levels for errors and report them
+	/// (exiting with an error code if any).
+	#[arg(long = "validate")]
+	validate: bool,
+	/// Instead of playing, list the ids and names of the levels.
+	#[arg(long = "list-levels")]
+	list_levels: bool,
+	/// Start in the level of the given replay file and play its inputs back.
+	#[arg(long = "replay", value_name = "FILE")]
+	replay_path: Option<PathBuf>,
+	/// Use the given PNG file as the spritesheet instead of the built-in one,
+	/// can also be given by the PUZH_SPRITESHEET environment variable.
+	#[arg(long = "spritesheet", value_name = "FILE")]
+	spritesheet_path: Option<PathBuf>,
+}
+
+impl Game {
+	pub fn new(ctx: &mut Context, settings: CommandLineSettings) -> GameResult<Game> {
+		let mut levels = Levels::load(settings.preload);
+		let mut warnings = vec![];
+		let replay = match settings.replay_path.as_deref().map(Replay::load) {
+			Some(Ok(replay)) => Some(replay),
+			Some(Err(error)) => {
+				let warning = format!("error: {error}");
+				println!("{warning}");
+				warnings.push(warning);
+				None
+			},
+			None => None,
+		};
+		let level_id = match &replay {
+			Some(replay) => &replay.level_id,
+			None => settings.level_id.as_deref().unwrap_or("test"),
+		};
+		let looks_like_path = level_id.contains('/')
+			|| level_id.contains(std::path::MAIN_SEPARATOR)
+			|| Path::new(level_id).is_file();
+		let level = if looks_like_path {
+			levels.insert_from_file(Path::new(level_id))
+		} else {
+			levels.get(level_id)
+		};
+		let level = match level {
+			Ok(level) => level,
+			Err(error) => {
+				let warning = format!("error: {error}");
+				println!("{warning}");
+				warnings.push(warning);
+				levels.get("test").unwrap()
+			},
+		};
+		let level_id = level.id.clone();
+		// Without a level to start in, the player picks one.
+		let open_menu = settings.level_id.is_none() && replay.is_none();
+		let sim = Simulation::new(&level);
+		let notes = level.notes.clone();
+		let spritesheet_path = settings
+			.spritesheet_path
+			.clone()
+			.or_else(|| std::env::var_os("PUZH_SPRITESHEET").map(PathBuf::from));
+		let spritesheet = match spritesheet_path {
+			Some(path) => match load_spritesheet(ctx, &path) {
+				Ok(spritesheet) => Some(spritesheet),
+				Err(error) => {
+					let warning = format!("warning: {error}, using the built-in spritesheet");
+					println!("{warning}");
+					warnings.push(warning);
+					None
+				},
+			},
+			None => None,
+		};
+		let spritesheet = match spritesheet {
+			Some(spritesheet) => spritesheet,
+			None => Image::from_bytes(ctx, include_bytes!("../assets/spritesheet.png"))?,
+		};
+		let (keybindings, audio_settings) = load_config(&mut warnings);
+		let mut game = Game {
+			state: GameState::Playing,
+			levels,
+			level,
+			entry_name: None,
+			delivered_objects: HashMap::new(),
+			sim,
+			notes,
+			note_page: 0,
+			rays_animation: None,
+			fuse_start: None,
+			blasts: vec![],
+			objective_banner: None,
+			dirty: true,
+			trail: VecDeque::new(),
+			show_trail: settings.trail,
+			screen_shake: !settings.no_screen_shake,
+			shake: None,
+			particles: vec![],
+			show_stats: false,
+			show_debug: false,
+			last_move_outcome: None,
+			mouse_position: Vec2::ZERO,
+			player_facing_left: false,
+			show_grid_lines: false,
+			inputs: vec![],
+			replay_inputs: VecDeque::new(),
+			ghost: None,
+			recording: None,
+			undo_stack: vec![],
+			redo_stack: vec![],
+			object_instances: graphics::InstanceArray::new_ordered(ctx, spritesheet.clone()),
+			spritesheet,
+			cheese_count: 0,
+			step_count_at_level_start: 0,
+			reset_count: 0,
+			death_count: 0,
+			won: false,
+			best_steps: load_best_steps(),
+			warnings,
+			reload_warnings: vec![],
+			window_grid_size: (Grid::DEFAULT_W, Grid::DEFAULT_H),
+			window_size: Vec2::from(ctx.gfx.drawable_size()),
+			fullscreen: false,
+			sounds: SoundEffect::ALL
+				.into_iter()
+				.map(|effect| (effect, audio::SoundData::from_bytes(effect.wav_data())))
+				.collect(),
+			music: None,
+			audio_settings,
+			input_queue: VecDeque::new(),
+			keybindings,
+			ground_layer: None,
+		};
+		game.go_to_level(&level_id, None);
+		if let Some(replay) = replay {
+			game.replay_inputs = replay.inputs.into();
+		}
+		if open_menu {
+			game.open_menu();
+		}
+		game.start_music(ctx);
+		Ok(game)
+	}
+
+	/// Inputs pressed during animations beyond that are dropped,
+	/// a longer queue would make the player lag behind the keyboard.
+	const INPUT_QUEUE_CAPACITY: usize = 2;
+
+	/// The window shows at most that many tiles, larger grids are scrolled over.
+	const MAX_VIEW_W: i32 = 16;
+	const MAX_VIEW_H: i32 = 12;
+
+	/// Size of the part of the grid that is shown, in pixels.
+	fn view_size(&self) -> Vec2 {
+		Vec2::new(
+			self.window_grid_size.0 as f32 * Tile::W,
+			self.window_grid_size.1 as f32 * Tile::H,
+		)
+	}
+
+	/// Where the view is in the window, scaled to fit it as much as possible while keeping
+	/// its aspect ratio, the unused space on the sides is left black.
+	fn view_in_window(&self) -> Rect {
+		let view_size = self.view_size();
+		let scale = (self.window_size.x / view_size.x).min(self.window_size.y / view_size.y);
+		let size = view_size * scale;
+		let corner = (self.window_size - size) / 2.0;
+		Rect::new(corner.x, corner.y, size.x, size.y)
+	}
+
+	/// Top left corner of the part of the grid that is shown, in pixels.
+	/// It keeps the player centered, without showing past the edges of the grid.
+	fn camera(&self) -> Vec2 {
+		let grid_size = Vec2::new(
+			self.sim.grid.w as f32 * Tile::W,
+			self.sim.grid.h as f32 * Tile::H,
+		);
+		let view_size = self.view_size();
+		let target = match self.sim.first_player_coords() {
+			Some(coords) => Vec2::from(tile_rect(coords).center()),
+			None => grid_size / 2.0,
+		};
+		(target - view_size / 2.0).clamp(Vec2::ZERO, (grid_size - view_size).max(Vec2::ZERO))
+	}
+
+	/// The coords of the tile under the given point of the window (that may be out of the grid).
+	fn window_to_grid_coords(&self, window_position: Vec2) -> Point2<i32> {
+		let view_in_window = self.view_in_window();
+		let scale = view_in_window.w / self.view_size().x;
+		let view_position = (window_position - Vec2::new(view_in_window.x, view_in_window.y)) / scale;
+		let grid_position = (self.camera() + view_position) / Vec2::new(Tile::W, Tile::H);
+		Point2::from([
+			grid_position.x.floor() as i32,
+			grid_position.y.floor() as i32,
+		])
+	}
+
+	/// Describes what is in the tile under the cursor.
+	fn debug_text(&self) -> String {
+		let coords = self.window_to_grid_coords(self.mouse_position);
+		let Some(tile) = self.sim.grid.get(coords) else {
+			return format!("({}, {}) out of the grid", coords.x, coords.y);
+		};
+		let obj = match &tile.obj {
+			Some(obj) => obj.kind.descr().unwrap_or_else(|| "unknown".to_string()),
+			None => "none".to_string(),
+		};
+		let ground = match &tile.ground {
+			Ground::OpenDoor { .. } => "open door".to_string(),
+			ground => ground.descr().unwrap_or_else(|| "unknown".to_string()),
+		};
+		let mut text = format!("({}, {}) obj: {obj}, ground: {ground}", coords.x, coords.y);
+		for exit in tile.exits.iter() {
+			text += &format!(
+				", exit {} to {}",
+				direction_descr(exit.direction).unwrap_or("unknown"),
+				exit.dst_level_id
+			);
+			if let Some(entry_name) = &exit.entry_name {
+				text += &format!(" ({entry_name})");
+			}
+		}
+		if let Some(outcome) = &self.last_move_outcome {
+			text += &format!(
+				" | last move: {} moved, {} cheese",
+				outcome.moved.len(),
+				outcome.cheese_gained
+			);
+			if outcome.some_move_failed {
+				text += ", blocked";
+			}
+		}
+		text
+	}
+
+	/// Tells if the player can move right now, otherwise their inputs are queued.
+	fn can_move_now(&self) -> bool {
+		!self.won
+			&& !self.sim.is_out_of_steps()
+			&& self.sim.is_settled()
+			&& self.sim.grid.tiles.iter().all(|tile| {
+				tile
+					.obj
+					.as_ref()
+					.is_none_or(|obj| obj.animation.is_finished())
+			})
+	}
+
+	/// Tells if something on screen is changing on its own (without any input).
+	fn is_animating(&self) -> bool {
+		matches!(
+			self.state,
+			GameState::Dying { .. } | GameState::LevelTransition { .. }
+		) || !self.sim.is_settled()
+			|| self
+				.blasts
+				.iter()
+				.any(|blast| blast.time_start.elapsed() < Blast::DURATION)
+			|| !self.particles.is_empty()
+			|| self
+				.shake
+				.as_ref()
+				.is_some_and(|shake| shake.time_start.elapsed() < Shake::DURATION)
+			|| self
+				.objective_banner
+				.as_ref()
+				.is_some_and(|banner| banner.time_start.elapsed() < banner.duration)
+			|| self.sim.grid.tiles.iter().any(|tile| {
+				tile
+					.obj
+					.as_ref()
+					.is_some_and(|obj| !obj.animation.is_finished())
+			})
+	}
+
+	/// How long the fade between levels takes, from the level left to the level entered.
+	const LEVEL_TRANSITION_DURATION: Duration = Duration::from_millis(400);
+
+	fn start_level_transition(&mut self, exit: Exit) {
+		self.input_queue.clear();
+		self.state = GameState::LevelTransition { exit: Some(exit), time_start: Instant::now() };
+	}
+
+	/// The player leaves the level through the given exit, what it got here is kept.
+	fn take_exit(&mut self, exit: Exit) {
+		self.cheese_count += self.sim.cheese_count_got_here;
+		self.step_count_at_level_start += self.sim.step_count;
+		// What was delivered to the level being left stayed in it.
+		self.delivered_objects.remove(&self.level.id);
+		for (kind, exit) in std::mem::take(&mut self.sim.sent_objects) {
+			self
+				.delivered_objects
+				.entry(exit.dst_level_id)
+				.or_default()
+				.push((kind, exit.entry_name));
+		}
+		self.go_to_level(&exit.dst_level_id, exit.entry_name.as_deref());
+	}
+
+	/// Starts an attempt at the given level. The provisional cheese and objects sent away
+	/// from the current level are lost, use `take_exit` to keep them.
+	fn go_to_level(&mut self, level_id: &str, entry_name: Option<&str>) {
+		let new_level = match self.levels.get(level_id) {
+			Ok(level) => level,
+			Err(error) => {
+				let warning = format!("error: {error}");
+				println!("{warning}");
+				self.warnings.push(warning);
+				return;
+			},
+		};
+		self.level = new_level;
+		self.entry_name = entry_name.map(|entry_name| entry_name.to_string());
+		if self.level.entry(entry_name).is_none() {
+			let warning = format!(
+				"warning: level \"{}\" has no entry named \"{}\", using its unnamed entry",
+				self.level.id,
+				entry_name.unwrap()
+			);
+			println!("{warning}");
+			self.warnings.push(warning);
+			self.entry_name = None;
+		}
+		self.restart_attempt();
+		self.ghost = Replay::load_best(&self.level.id).map(|inputs| Ghost::new(inputs, &self.sim));
+		self.notes = self.level.notes.clone();
+		self.note_page = self
+			.notes
+			.iter()
+			.filter_map(|note| note.page)
+			.min()
+			.unwrap_or(0);
+		self.objective_banner = self.level.objective().map(|text| Banner {
+			text,
+			time_start: Instant::now(),
+			duration: Duration::from_secs_f32(3.0),
+		});
+	}
+
+	/// Starts the current level over, as it is when entering it.
+	/// Happens when entering a level, resetting it and dying in it.
+	fn restart_attempt(&mut self) {
+		let (entry_coords, entry_direction) = self.level.entry(self.entry_name.as_deref()).unwrap();
+		self.sim = Simulation::new_at_entry(&self.level, entry_coords, entry_direction);
+		self.player_facing_left = entry_direction.x < 0;
+		if let Some(delivered_objects) = self.delivered_objects.get(&self.level.id) {
+			for (kind, entry_name) in delivered_objects.iter() {
+				let (coords, direction) = self
+					.level
+					.entry(entry_name.as_deref())
+					.unwrap_or((self.level.entry_coords, self.level.entry_direction));
+				self.sim.place_at_entry(kind.clone(), coords, direction);
+			}
+		}
+		self.fuse_start = None;
+		self.blasts.clear();
+		self.won = false;
+		self.trail.clear();
+		self.inputs.clear();
+		self.replay_inputs.clear();
+		if let Some(ghost) = &mut self.ghost {
+			ghost.restart(&self.sim);
+		}
+		self.undo_stack.clear();
+		self.redo_stack.clear();
+		self.input_queue.clear();
+		self.last_move_outcome = None;
+		self.push_to_trail();
+	}
+
+	/// How long lit bombs wait before exploding.
+	const FUSE_DURATION: Duration = Duration::from_millis(300);
+
+	fn reset_level(&mut self) {
+		self.reset_count += 1;
+		self.restart_attempt();
+	}
+
+	/// How long the level stays frozen after the player dies, before restarting.
+	const DEATH_DURATION: Duration = Duration::from_millis(600);
+
+	/// To be called after the simulation advanced, the attempt restarts if the player died.
+	fn handle_death(&mut self) {
+		if self.sim.player_died {
+			self.death_count += 1;
+			self.input_queue.clear();
+			self.state = GameState::Dying { time_start: Instant::now() };
+		}
+	}
+
+	fn pause_key_down(&mut self, ctx: &mut Context, input: KeyInput) -> GameResult {
+		let action = input.keycode.and_then(|key| self.keybindings.action(key));
+		let GameState::Paused { selected, confirming_quit } = &mut self.state else {
+			return Ok(());
+		};
+		if *confirming_quit {
+			match input.keycode {
+				Some(VirtualKeyCode::Return | VirtualKeyCode::NumpadEnter | VirtualKeyCode::Y) => {
+					match selected {
+						PauseOption::QuitToMenu => self.open_menu(),
+						_ => ctx.request_quit(),
+					}
+				},
+				Some(VirtualKeyCode::Escape | VirtualKeyCode::N) => *confirming_quit = false,
+				_ => {},
+			}
+			return Ok(());
+		}
+		match (action, input.keycode) {
+			(Some(Action::Move(direction)), _) => {
+				let index = PauseOption::ALL
+					.iter()
+					.position(|option| option == selected)
+					.unwrap();
+				let index = (index as i32 + direction.y).clamp(0, PauseOption::ALL.len() as i32 - 1);
+				*selected = PauseOption::ALL[index as usize];
+			},
+			(_, Some(VirtualKeyCode::Return | VirtualKeyCode::NumpadEnter)) => match selected {
+				PauseOption::Resume => self.state = GameState::Playing,
+				PauseOption::Reset => {
+					self.state = GameState::Playing;
+					self.reset_level();
+				},
+				PauseOption::QuitToMenu | PauseOption::QuitGame => *confirming_quit = true,
+			},
+			(_, Some(VirtualKeyCode::Escape | VirtualKeyCode::P)) => self.state = GameState::Playing,
+			(_, Some(VirtualKeyCode::F11)) => self.toggle_fullscreen(ctx),
+			_ => {},
+		}
+		Ok(())
+	}
+
+	/// Dims the frozen level and lists the pause options over it.
+	fn draw_pause_menu(&self, ctx: &mut Context, canvas: &mut Canvas) -> GameResult {
+		let GameState::Paused { selected, confirming_quit } = self.state else {
+			return Ok(());
+		};
+		let view_size = self.view_size();
+		canvas.draw(
+			&graphics::Mesh::new_rectangle(
+				ctx,
+				graphics::DrawMode::fill(),
+				Rect::new(0.0, 0.0, view_size.x, view_size.y),
+				Color::new(0.0, 0.0, 0.0, 0.6),
+			)?,
+			DrawParam::default().z(10),
+		);
+
+		let scale = 40.0;
+		let mut lines = vec![("Paused".to_string(), scale, Color::WHITE)];
+		for option in PauseOption::ALL {
+			let is_selected = option == selected;
+			let cursor = if is_selected { "> " } else { "" };
+			let color = if is_selected {
+				Color::new(1.0, 0.9, 0.2, 1.0)
+			} else {
+				Color::new(0.8, 0.8, 0.8, 1.0)
+			};
+			lines.push((format!("{cursor}{}", option.text()), scale * 0.6, color));
+		}
+		if confirming_quit {
+			lines.push((
+				"The progress in the level will be lost, quit? (Enter / Escape)".to_string(),
+				scale * 0.5,
+				Color::new(1.0, 0.4, 0.3, 1.0),
+			));
+		}
+		let top = view_size.y / 2.0 - lines.len() as f32 * scale / 2.0;
+		for (line_index, (line, line_scale, color)) in lines.into_iter().enumerate() {
+			let mut text = graphics::Text::new(line);
+			text.set_scale(line_scale);
+			text.set_layout(graphics::TextLayout::center());
+			let center = Vec2::new(view_size.x / 2.0, top + (line_index as f32 + 0.5) * scale);
+			canvas.draw(&text, DrawParam::default().z(11).color(color).dest(center));
+		}
+		Ok(())
+	}
+
+	/// Helps with finding the coords to use in level files.
+	fn draw_grid_lines(&self, ctx: &mut Context, canvas: &mut Canvas) -> GameResult {
+		let grid = &self.sim.grid;
+		let grid_size = Vec2::new(grid.w as f32 * Tile::W, grid.h as f32 * Tile::H);
+		let color = Color::new(0.0, 0.0, 0.0, 0.4);
+		let mut lines = graphics::MeshBuilder::new();
+		for grid_x in 0..=grid.w {
+			let x = grid_x as f32 * Tile::W;
+			lines.line(&[Vec2::new(x, 0.0), Vec2::new(x, grid_size.y)], 1.0, color)?;
+		}
+		for grid_y in 0..=grid.h {
+			let y = grid_y as f32 * Tile::H;
+			lines.line(&[Vec2::new(0.0, y), Vec2::new(grid_size.x, y)], 1.0, color)?;
+		}
+		canvas.draw(
+			&graphics::Mesh::from_data(ctx, lines.build()),
+			DrawParam::default().z(6),
+		);
+		for grid_y in 0..grid.h {
+			for grid_x in 0..grid.w {
+				let mut text = graphics::Text::new(format!("{grid_x},{grid_y}"));
+				text.set_scale(10.0);
+				let corner = tile_rect(Point2::from([grid_x, grid_y])).point();
+				canvas.draw(
+					&text,
+					DrawParam::default()
+						.z(6)
+						.color(color)
+						.dest(Vec2::from(corner) + Vec2::new(2.0, 1.0)),
+				);
+			}
+		}
+		Ok(())
+	}
+
+	fn draw_stats(&self, ctx: &mut Context, canvas: &mut Canvas) -> GameResult {
+		let step_count = self.step_count_at_level_start + self.sim.step_count;
+		let mut lines = vec![
+			("Statistics".to_string(), 30.0),
+			(format!("{step_count} steps"), 20.0),
+			(format!("{} resets", self.reset_count), 20.0),
+			(format!("{} deaths", self.death_count), 20.0),
+			(format!("{} levels completed", self.best_steps.len()), 20.0),
+			(format!("{} cheese", self.cheese_count), 20.0),
+		];
+		if !self.best_steps.is_empty() {
+			lines.push(("Best steps:".to_string(), 20.0));
+			let mut best_steps: Vec<_> = self.best_steps.iter().collect();
+			best_steps.sort();
+			for (level_id, best) in best_steps {
+				lines.push((format!("  {level_id}: {best}"), 20.0));
+			}
+		}
+
+		let margin = 10.0;
+		let width = 300.0;
+		let height = lines.iter().map(|(_line, scale)| scale).sum::<f32>() + margin * 2.0;
+		let view_size = self.view_size();
+		let corner = Vec2::new(view_size.x - width - margin, margin);
+		canvas.draw(
+			&graphics::Mesh::new_rectangle(
+				ctx,
+				graphics::DrawMode::fill(),
+				Rect::new(corner.x, corner.y, width, height),
+				Color::new(0.0, 0.0, 0.0, 0.7),
+			)?,
+			DrawParam::default().z(10),
+		);
+		let mut text_y = corner.y + margin;
+		for (line, scale) in lines {
+			let mut text = graphics::Text::new(line);
+			text.set_scale(scale);
+			canvas.draw(
+				&text,
+				DrawParam::default()
+					.z(11)
+					.color(Color::WHITE)
+					.dest(Vec2::new(corner.x + margin, text_y)),
+			);
+			text_y += scale;
+		}
+		Ok(())
+	}
+
+	/// Lists all the levels (which parses them all) to choose from, the current one being selected.
+	fn open_menu(&mut self) {
+		let levels = self.levels.all();
+		let selected = levels
+			.iter()
+			.position(|level| level.id == self.level.id)
+			.unwrap_or(0);
+		self.state = GameState::Menu { levels, selected };
+		self.input_queue.clear();
+		self.dirty = true;
+	}
+
+	fn menu_key_down(&mut self, ctx: &mut Context, input: KeyInput) -> GameResult {
+		let action = input.keycode.and_then(|key| self.keybindings.action(key));
+		let GameState::Menu { levels, selected } = &mut self.state else {
+			return Ok(());
+		};
+		match (action, input.keycode) {
+			(Some(Action::Move(direction)), _) => {
+				*selected = (*selected as i32 + direction.y).clamp(0, levels.len() as i32 - 1) as usize;
+			},
+			(_, Some(VirtualKeyCode::Return | VirtualKeyCode::NumpadEnter)) => {
+				let level_id = levels[*selected].id.clone();
+				self.state = GameState::Playing;
+				self.go_to_level(&level_id, None);
+			},
+			(_, Some(VirtualKeyCode::Escape)) => ctx.request_quit(),
+			(_, Some(VirtualKeyCode::F11)) => self.toggle_fullscreen(ctx),
+			_ => {},
+		}
+		Ok(())
+	}
+
+	/// Draws the list of levels over the whole view, scrolled to keep the selected one in sight.
+	fn draw_menu(&self, ctx: &mut Context, canvas: &mut Canvas) -> GameResult {
+		let GameState::Menu { levels, selected } = &self.state else {
+			return Ok(());
+		};
+		let view_size = self.view_size();
+		canvas.draw(
+			&graphics::Mesh::new_rectangle(
+				ctx,
+				graphics::DrawMode::fill(),
+				Rect::new(0.0, 0.0, view_size.x, view_size.y),
+				Color::new(0.1, 0.1, 0.15, 1.0),
+			)?,
+			DrawParam::default().z(0),
+		);
+
+		let title_scale = 40.0;
+		let mut text = graphics::Text::new("Levels");
+		text.set_scale(title_scale);
+		canvas.draw(
+			&text,
+			DrawParam::default()
+				.z(1)
+				.color(Color::WHITE)
+				.dest(Vec2::new(10.0, 10.0)),
+		);
+
+		let scale = 24.0;
+		let list_y = 10.0 + title_scale + 10.0;
+		let visible_count = (((view_size.y - list_y) / scale) as usize).max(1);
+		let first = selected
+			.saturating_sub(visible_count / 2)
+			.min(levels.len().saturating_sub(visible_count));
+		for (line_index, (level_index, level)) in levels
+			.iter()
+			.enumerate()
+			.skip(first)
+			.take(visible_count)
+			.enumerate()
+		{
+			let is_selected = level_index == *selected;
+			let error_count = level.error_messages.len();
+			let errors = match error_count {
+				0 => "".to_string(),
+				1 => " (1 error)".to_string(),
+				_ => format!(" ({error_count} errors)"),
+			};
+			let cursor = if is_selected { "> " } else { "  " };
+			let mut text =
+				graphics::Text::new(format!("{cursor}{}  {}{errors}", level.id, level.name));
+			text.set_scale(scale);
+			let color = match (is_selected, error_count) {
+				(true, 0) => Color::new(1.0, 0.9, 0.2, 1.0),
+				(true, _) => Color::new(1.0, 0.4, 0.3, 1.0),
+				(false, 0) => Color::WHITE,
+				(false, _) => Color::new(0.8, 0.2, 0.2, 1.0),
+			};
+			canvas.draw(
+				&text,
+				DrawParam::default()
+					.z(1)
+					.color(color)
+					.dest(Vec2::new(10.0, list_y + line_index as f32 * scale)),
+			);
+		}
+		Ok(())
+	}
+
+	/// Reads the file of the current level again and restarts the level,
+	/// so that changes to the level file can be seen without restarting the game.
+	fn reload_level(&mut self) {
+		let Some(level_path) = self.level.path.clone() else {
+			println!("warning: the current level has no level file to reload");
+			return;
+		};
+		let level = match Level::load_from_file(&level_path) {
+			Ok(level) => level,
+			Err(error) => {
+				let warning = format!("warning: failed to reload {error}");
+				println!("{warning}");
+				self.reload_warnings = vec![warning];
+				return;
+			},
+		};
+		self.reload_warnings.clear();
+		let level_id = self.levels.insert(level).id.clone();
+		// The attempt restarts, the cheese and steps of the previous levels are kept.
+		let entry_name = self.entry_name.clone();
+		self.go_to_level(&level_id, entry_name.as_deref());
+	}
+
+	/// The music is optional, the game is fine without it.
+	fn start_music(&mut self, ctx: &Context) {
+		let Ok(music_data) = std::fs::read("assets/music.ogg") else {
+			return;
+		};
+		let music = audio::Source::from_data(ctx, audio::SoundData::from(music_data));
+		let result = music.and_then(|mut music| {
+			music.set_repeat(true);
+			music.set_volume(self.audio_settings.effective_volume());
+			music.play(ctx)?;
+			Ok(music)
+		});
+		match result {
+			Ok(music) => self.music = Some(music),
+			Err(error) => println!("warning: failed to play the music: {error}"),
+		}
+	}
+
+	/// Applies and saves changes to the audio settings.
+	fn change_audio_settings(&mut self, change: impl FnOnce(&mut AudioSettings)) {
+		change(&mut self.audio_settings);
+		self.audio_settings.volume = self.audio_settings.volume.clamp(0.0, 1.0);
+		if let Some(music) = &mut self.music {
+			music.set_volume(self.audio_settings.effective_volume());
+		}
+		if let Err(error) = self.audio_settings.save() {
+			println!("warning: failed to save the audio settings: {error}");
+		}
+	}
+
+	/// Plays the sounds of what happened in the simulation since last time.
+	/// Shakes the view if something hit hard, to be called before the sounds are played.
+	fn start_shake(&mut self) {
+		if !self.screen_shake {
+			return;
+		}
+		let intensity = if self.sim.sound_effects.contains(&SoundEffect::Explosion) {
+			6.0
+		} else if self.sim.sound_effects.contains(&SoundEffect::FailToMove) {
+			2.0
+		} else {
+			return;
+		};
+		self.shake = Some(Shake { intensity, time_start: Instant::now() });
+	}
+
+	fn play_sound_effects(&mut self, ctx: &Context) {
+		let mut sound_effects = std::mem::take(&mut self.sim.sound_effects);
+		// Many objects can move at once, one sound of each is enough.
+		sound_effects.sort();
+		sound_effects.dedup();
+		// Pushing is what is worth hearing, not the pusher moving.
+		if sound_effects.contains(&SoundEffect::Push) {
+			sound_effects.retain(|&effect| effect != SoundEffect::Move);
+		}
+		for effect in sound_effects {
+			let Some(sound_data) = self.sounds.get(&effect) else {
+				continue;
 			};
-			obj_is_rope = matches!(obj.as_mut().unwrap().kind, ObjKind::Rope);
-			self.grid.get_mut(coords_dst.into()).unwrap().obj = obj;
+			let volume = self.audio_settings.effective_volume();
+			let result = audio::Source::from_data(ctx, sound_data.clone()).and_then(|mut source| {
+				source.set_volume(volume);
+				source.play_detached(ctx)
+			});
+			if let Err(error) = result {
+				println!("warning: failed to play a sound, going on without sound: {error}");
+				self.sounds.clear();
+			}
+		}
+	}
 
-			if let Some(mut soap) = soap_getting_back.take() {
-				if matches!(soap.animation, Animation::None) {
-					soap.animation = Animation::CommingFrom {
-						src: coords_dst.into(),
-						time_start: Instant::now(),
-						duration: Duration::from_secs_f32(0.05),
-					};
-					soap.moved = true;
-				}
-				self.grid.get_mut(coords).unwrap().obj = Some(soap);
+	/// Back in a window, the window is sized to the current level
+	/// (that may not be the level it was sized to before going fullscreen).
+	fn toggle_fullscreen(&mut self, ctx: &mut Context) {
+		let fullscreen_type = if self.fullscreen {
+			FullscreenType::Windowed
+		} else {
+			FullscreenType::Desktop
+		};
+		if let Err(error) = ctx.gfx.set_fullscreen(fullscreen_type) {
+			println!("warning: failed to toggle fullscreen: {error}");
+			return;
+		}
+		self.fullscreen = !self.fullscreen;
+		if !self.fullscreen {
+			let (grid_w, grid_h) = self.window_grid_size;
+			let result = ctx
+				.gfx
+				.set_drawable_size(grid_w as f32 * Tile::W, grid_h as f32 * Tile::H);
+			if let Err(error) = result {
+				println!("warning: failed to resize the window: {error}");
 			}
+		}
+	}
 
-			self.handle_sapling(false);
-		} else if failed_to_move {
-			if let Some(obj) = self.grid.get_mut(coords).unwrap().obj.as_mut() {
-				obj.animation = Animation::FailingToMoveTo {
-					dst: coords_dst.into(),
-					time_start: Instant::now(),
-					duration: Duration::from_secs_f32(0.05),
-				};
+	/// Shows the next page of notes, if any.
+	fn next_note_page(&mut self) {
+		let next_page = self
+			.notes
+			.iter()
+			.filter_map(|note| note.page)
+			.filter(|&page| page > self.note_page)
+			.min();
+		if let Some(next_page) = next_page {
+			self.note_page = next_page;
+		}
+	}
+
+	fn push_to_trail(&mut self) {
+		const TRAIL_LENGTH: usize = 8;
+		if let Some(coords) = self.sim.first_player_coords() {
+			if self.trail.back() != Some(&coords) {
+				self.trail.push_back(coords);
+				if self.trail.len() > TRAIL_LENGTH {
+					self.trail.pop_front();
+				}
 			}
 		}
+	}
 
-		if shall_move && !pushed {
-			let coords_maybe_pulled = IVec2::from(coords) - direction;
-			if obj_is_rope
-				|| self
-					.grid
-					.get(coords_maybe_pulled.into())
-					.is_some_and(|tile| {
-						tile
-							.obj
-							.as_ref()
-							.is_some_and(|obj| matches!(obj.kind, ObjKind::Rope))
-					}) {
-				self.obj_move(coords_maybe_pulled.into(), direction, false);
+	fn start_or_stop_recording(&mut self) {
+		if let Some(recording) = self.recording.take() {
+			if recording.frames.is_empty() {
+				return;
 			}
+			let time = std::time::SystemTime::now()
+				.duration_since(std::time::UNIX_EPOCH)
+				.unwrap_or_default()
+				.as_secs();
+			let path = Path::new("recordings").join(format!("puzh-{time}.gif"));
+			println!("saving recording to {} ...", path.display());
+			// Encoding takes a while, the game should not freeze in the meantime.
+			std::thread::spawn(move || match recording.encode_gif(&path) {
+				Ok(()) => println!("saved recording to {}", path.display()),
+				Err(error) => println!("error: failed to save recording: {error}"),
+			});
+		} else {
+			println!("recording started");
+			self.recording = Some(Recording::new());
 		}
 	}
 
-	fn go_to_level(&mut self, level_id: &str) {
-		let new_level = self.all_levels.get(level_id).unwrap().clone();
-		self.cheese_count += self.cheese_count_got_here;
-		self.cheese_count_got_here = 0;
-		self.step_count_at_level_start = self.step_count;
-		self.level = new_level;
-		self.grid = self.level.grid.clone();
-		self.rays = vec![];
-		self.notes = self.level.notes.clone();
-		let entry_coords = self.level.entry_coords;
-		let entry_direction = self.level.entry_direction;
-		self.grid.get_mut(entry_coords).unwrap().obj = Some(Obj::from_kind(ObjKind::Player));
-		self
-			.grid
-			.get_mut(entry_coords)
-			.unwrap()
-			.obj
-			.as_mut()
-			.unwrap()
-			.animation = Animation::CommingFrom {
-			src: (IVec2::from(entry_coords) - entry_direction).into(),
-			time_start: Instant::now(),
-			duration: Duration::from_secs_f32(0.05),
+	/// Captures the last drawn frame into the recording, if recording.
+	fn record_frame(&mut self, ctx: &mut Context) {
+		let Some(recording) = &mut self.recording else {
+			return;
+		};
+		if recording
+			.last_frame_time
+			.is_some_and(|time| time.elapsed() < Recording::FRAME_DURATION)
+		{
+			return;
+		}
+		recording.last_frame_time = Some(Instant::now());
+		let frame = ctx.gfx.frame().clone();
+		let is_bgra = matches!(
+			frame.format(),
+			graphics::ImageFormat::Bgra8Unorm | graphics::ImageFormat::Bgra8UnormSrgb
+		);
+		let pixels = match frame.to_pixels(ctx) {
+			Ok(pixels) => pixels,
+			Err(error) => {
+				println!("error: failed to capture a frame, recording aborted: {error}");
+				self.recording = None;
+				return;
+			},
 		};
+		let width = frame.width() / Recording::DOWNSCALE;
+		let height = frame.height() / Recording::DOWNSCALE;
+		let mut downscaled_pixels = Vec::with_capacity((width * height * 4) as usize);
+		for y in 0..height {
+			for x in 0..width {
+				let index = (((y * frame.width() + x) * Recording::DOWNSCALE) * 4) as usize;
+				let [r, g, b, _a] = pixels[index..(index + 4)].try_into().unwrap();
+				let (r, b) = if is_bgra { (b, r) } else { (r, b) };
+				downscaled_pixels.extend([r, g, b, 255]);
+			}
+		}
+		recording.frames.push(RecordedFrame {
+			pixels: downscaled_pixels,
+			width: width as u16,
+			height: height as u16,
+		});
+		if recording.frames.len() >= Recording::MAX_FRAME_COUNT {
+			self.start_or_stop_recording();
+		}
 	}
 
-	fn player_move(&mut self, direction: IVec2) {
-		self.clear_processed_flags();
-		self.clear_moved_flags();
-		self.clear_animations();
+	fn snapshot(&self) -> Snapshot {
+		Snapshot {
+			grid: self.sim.grid.clone(),
+			facing: self.sim.facing,
+			step_count: self.sim.step_count,
+			cheese_count_got_here: self.sim.cheese_count_got_here,
+			sent_objects: self.sim.sent_objects.clone(),
+			inputs: self.inputs.clone(),
+		}
+	}
 
-		for grid_y in 0..Grid::H {
-			for grid_x in 0..Grid::W {
-				let coords = Point2::from([grid_x, grid_y]);
-				if let Some(obj) = &self.grid.get(coords).unwrap().obj {
-					if matches!(obj.kind, ObjKind::Player) && !obj.processed && !obj.moved {
-						self
-							.grid
-							.get_mut(coords)
-							.unwrap()
-							.obj
-							.as_mut()
-							.unwrap()
-							.processed = true;
-						self.obj_move(coords, direction, false);
-					}
-				}
-			}
+	fn restore_snapshot(&mut self, snapshot: Snapshot) {
+		self.sim.grid = snapshot.grid;
+		self.sim.facing = snapshot.facing;
+		self.sim.step_count = snapshot.step_count;
+		self.sim.cheese_count_got_here = snapshot.cheese_count_got_here;
+		self.sim.sent_objects = snapshot.sent_objects;
+		self.inputs = snapshot.inputs;
+		self.catch_up_ghost();
+		self.sim.rays = vec![];
+		self.sim.lit_bombs = vec![];
+		self.fuse_start = None;
+		self.sim.clear_animations();
+		self.input_queue.clear();
+		self.update_won();
+	}
+
+	fn catch_up_ghost(&mut self) {
+		if let Some(ghost) = &mut self.ghost {
+			ghost.catch_up(self.inputs.len());
 		}
+	}
 
-		self.step_count += 1;
-		self.handle_sapling(true);
-		self.handle_bunnies();
-		self.handle_sapling(true);
+	/// To be called before the player does something that can be undone.
+	fn push_undo_snapshot(&mut self) {
+		self.undo_stack.push(self.snapshot());
+		self.redo_stack.clear();
 	}
 
-	fn player_shoot(&mut self) {
-		self.clear_processed_flags();
-		self.clear_moved_flags();
-		self.clear_animations();
+	fn undo(&mut self) {
+		if let Some(snapshot) = self.undo_stack.pop() {
+			self.redo_stack.push(self.snapshot());
+			self.restore_snapshot(snapshot);
+		}
+	}
 
-		for grid_y in 0..Grid::H {
-			for grid_x in 0..Grid::W {
-				let coords = Point2::from([grid_x, grid_y]);
-				if let Some(obj) = &self.grid.get(coords).unwrap().obj {
-					if matches!(obj.kind, ObjKind::Player) && !obj.processed {
-						self
-							.grid
-							.get_mut(coords)
-							.unwrap()
-							.obj
-							.as_mut()
-							.unwrap()
-							.processed = true;
-						for move_to_neighboor in [(1, 0), (0, 1), (-1, 0), (0, -1)] {
-							let (dx, dy) = move_to_neighboor;
-							let player_to_neighboor = IVec2::from([dx, dy]);
-							let neighboor_coords = IVec2::from(coords) + player_to_neighboor;
-							if let Some(neighboor_obj) = &self
-								.grid
-								.get(neighboor_coords.into())
-								.and_then(|tile| tile.obj.as_ref())
-							{
-								if let ObjKind::Raygun(kind) = neighboor_obj.kind.clone() {
-									self.rays.push(Ray {
-										coords: neighboor_coords.into(),
-										direction: player_to_neighboor,
-										action: match kind {
-											RaygunKind::SwapWithShooter => {
-												RayAction::SwapWith { with_who_coords: coords }
-											},
-											RaygunKind::DuplicateShootee => RayAction::Duplicate,
-											RaygunKind::TurnInto(into_what) => {
-												RayAction::TurnInto { into_what: *into_what }
-											},
-											RaygunKind::TurnIntoTurnInto => RayAction::TurnIntoTurnInto,
-										},
-									})
-								}
-							}
-						}
-					}
+	fn redo(&mut self) {
+		if let Some(snapshot) = self.redo_stack.pop() {
+			self.undo_stack.push(self.snapshot());
+			self.restore_snapshot(snapshot);
+		}
+	}
+
+	fn update_won(&mut self) {
+		let was_won = self.won;
+		self.won = self.sim.is_won();
+		if self.won && !was_won {
+			let steps = self.sim.step_count;
+			let best = self
+				.best_steps
+				.entry(self.level.id.clone())
+				.or_insert(steps);
+			let is_best = steps <= *best;
+			*best = steps.min(*best);
+			save_best_steps(&self.best_steps);
+			// Only the best solution is kept.
+			if is_best {
+				let replay = Replay { level_id: self.level.id.clone(), inputs: self.inputs.clone() };
+				let path = Replay::path(&self.level.id);
+				if let Err(error) = replay.save(&path) {
+					println!(
+						"warning: failed to save replay to {}: {error}",
+						path.display()
+					);
 				}
 			}
 		}
 	}
+
+	fn player_move(&mut self, direction: IVec2) {
+		let snapshot = self.snapshot();
+		self.inputs.push(Input::Move(direction));
+		self.catch_up_ghost();
+		if direction.x != 0 {
+			self.player_facing_left = direction.x < 0;
+		}
+		let mut outcome = self.sim.player_move(direction);
+		// Bumping into a wall is not worth an undo step.
+		if outcome.board_changed {
+			self.undo_stack.push(snapshot);
+			self.redo_stack.clear();
+		}
+		if let Some(exit) = outcome.exited_to.take() {
+			self.start_level_transition(exit);
+		} else {
+			self.push_to_trail();
+			self.update_won();
+			self.handle_death();
+		}
+		self.last_move_outcome = Some(outcome);
+	}
+
+	fn player_shoot(&mut self) {
+		self.push_undo_snapshot();
+		self.inputs.push(Input::Shoot);
+		self.catch_up_ghost();
+		self.sim.player_shoot();
+	}
 }
 
 impl EventHandler for Game {
-	fn update(&mut self, _ctx: &mut Context) -> GameResult {
-		if !self.rays.is_empty() {
+	fn update(&mut self, ctx: &mut Context) -> GameResult {
+		let grid_size = (
+			self.sim.grid.w.min(Game::MAX_VIEW_W),
+			self.sim.grid.h.min(Game::MAX_VIEW_H),
+		);
+		if self.window_grid_size != grid_size {
+			self.window_grid_size = grid_size;
+			// In fullscreen the view is just scaled to fit the screen.
+			if !self.fullscreen {
+				ctx.gfx
+					.set_drawable_size(grid_size.0 as f32 * Tile::W, grid_size.1 as f32 * Tile::H)?;
+			}
+			self.dirty = true;
+		}
+
+		if let GameState::Dying { time_start } = self.state {
+			if time_start.elapsed() >= Game::DEATH_DURATION {
+				self.state = GameState::Playing;
+				self.restart_attempt();
+			}
+		}
+
+		if let GameState::LevelTransition { exit, time_start } = &mut self.state {
+			let elapsed = time_start.elapsed();
+			let exit = if elapsed >= Game::LEVEL_TRANSITION_DURATION / 2 {
+				exit.take()
+			} else {
+				None
+			};
+			if let Some(exit) = exit {
+				self.take_exit(exit);
+			}
+			if elapsed >= Game::LEVEL_TRANSITION_DURATION {
+				self.state = GameState::Playing;
+			}
+		}
+
+		// The level is frozen when not playing.
+		if !matches!(self.state, GameState::Playing) {
+			return Ok(());
+		}
+
+		if !self.sim.rays.is_empty() {
 			if self.rays_animation.is_none() {
-				self.rays_animation = Some(RaysAnimation {
-					time_start: Instant::now(),
-					duration: Duration::from_secs_f32(0.02),
-				})
+				self.rays_animation =
+					Some(RaysAnimation { time_start: Instant::now(), duration: Ray::STEP_DURATION })
 			}
 
 			if let Some(RaysAnimation { time_start, duration }) = self.rays_animation {
 				let progress = time_start.elapsed().as_secs_f32() / duration.as_secs_f32();
 				if progress >= 1.0 {
 					self.rays_animation = None;
-					let mut rays_indices_to_remove = vec![];
-					for (ray_index, ray) in self.rays.iter_mut().enumerate() {
-						let dst_coords = IVec2::from(ray.coords) + ray.direction;
-						if let Some(dst_tile) = self.grid.get(dst_coords.into()) {
-							if dst_tile
-								.obj
-								.as_ref()
-								.is_some_and(|obj| matches!(obj.kind, ObjKind::WallWithHoles))
-							{
-								ray.coords = dst_coords.into();
-							} else if dst_tile
-								.obj
-								.as_ref()
-								.is_some_and(|obj| matches!(obj.kind, ObjKind::Mirror))
-							{
-								ray.coords = dst_coords.into();
-								ray.direction = -ray.direction;
-							} else if dst_tile
-								.obj
-								.as_ref()
-								.is_some_and(|obj| matches!(obj.kind, ObjKind::MirrorSlopeUp))
-							{
-								ray.coords = dst_coords.into();
-								let dir = ray.direction;
-								ray.direction.y = -dir.x;
-								ray.direction.x = -dir.y;
-							} else if dst_tile
-								.obj
-								.as_ref()
-								.is_some_and(|obj| matches!(obj.kind, ObjKind::MirrorSlopeDown))
-							{
-								ray.coords = dst_coords.into();
-								let dir = ray.direction;
-								ray.direction.y = dir.x;
-								ray.direction.x = dir.y;
-							} else if dst_tile.obj.is_some() {
-								match ray.action {
-									RayAction::SwapWith { with_who_coords } => {
-										rays_indices_to_remove.push(ray_index);
-										let shootee =
-											self.grid.get_mut(dst_coords.into()).unwrap().obj.take();
-										let shooter = self.grid.get_mut(with_who_coords).unwrap().obj.take();
-										self.grid.get_mut(dst_coords.into()).unwrap().obj = shooter;
-										self.grid.get_mut(with_who_coords).unwrap().obj = shootee;
-									},
-									RayAction::Duplicate => {
-										rays_indices_to_remove.push(ray_index);
-										let shootee_kind = self
-											.grid
-											.get(dst_coords.into())
-											.unwrap()
-											.obj
-											.as_ref()
-											.unwrap()
-											.kind
-											.clone();
-										let obj_to_be_duplicated_to =
-											&mut self.grid.get_mut(ray.coords).unwrap().obj;
-										if obj_to_be_duplicated_to.is_none() {
-											*obj_to_be_duplicated_to = Some(Obj::from_kind(shootee_kind));
-										}
-									},
-									RayAction::TurnInto { ref into_what } => {
-										rays_indices_to_remove.push(ray_index);
-										self.grid.get_mut(dst_coords.into()).unwrap().obj =
-											Some(Obj::from_kind(into_what.clone()));
-									},
-									RayAction::TurnIntoTurnInto => {
-										rays_indices_to_remove.push(ray_index);
-										let shootee = self
-											.grid
-											.get_mut(dst_coords.into())
-											.unwrap()
-											.obj
-											.take()
-											.unwrap();
-										self.grid.get_mut(dst_coords.into()).unwrap().obj =
-											Some(Obj::from_kind(ObjKind::Raygun(RaygunKind::TurnInto(
-												Box::new(shootee.kind),
-											))));
-									},
-								}
-							} else {
-								ray.coords = dst_coords.into();
-							}
-						} else {
-							rays_indices_to_remove.push(ray_index);
-						}
-					}
-					rays_indices_to_remove.sort();
-					for index_to_remove in rays_indices_to_remove.into_iter().rev() {
-						self.rays.remove(index_to_remove);
+					self.sim.advance_rays();
+					// Pushing rays can push the player through an exit.
+					if let Some(exit) = self.sim.taken_exit.take() {
+						self.start_level_transition(exit);
+					} else {
+						self.update_won();
+						self.handle_death();
 					}
-					self.handle_sapling(true);
 				}
 			}
 		}
 
+		if self.sim.rays.is_empty() && !self.sim.lit_bombs.is_empty() {
+			let fuse_start = *self.fuse_start.get_or_insert_with(Instant::now);
+			if fuse_start.elapsed() >= Game::FUSE_DURATION {
+				self.fuse_start = None;
+				for center in self.sim.lit_bombs.iter().copied() {
+					self
+						.blasts
+						.push(Blast { center, time_start: Instant::now() });
+				}
+				self.sim.detonate_bombs();
+				self.update_won();
+				self.handle_death();
+				self.dirty = true;
+			}
+		}
+		self
+			.blasts
+			.retain(|blast| blast.time_start.elapsed() < Blast::DURATION);
+		let delta = ctx.time.delta().as_secs_f32();
+		for particle in self.particles.iter_mut() {
+			particle.position += particle.velocity * delta;
+			particle.velocity *= Particle::DRAG.powf(delta);
+		}
+		self
+			.particles
+			.retain(|particle| particle.time_start.elapsed() < Particle::LIFETIME);
+
+		if !self.input_queue.is_empty() && self.can_move_now() {
+			let direction = self.input_queue.pop_front().unwrap();
+			self.player_move(direction);
+			self.dirty = true;
+		} else if !self.replay_inputs.is_empty() && self.can_move_now() {
+			match self.replay_inputs.pop_front().unwrap() {
+				Input::Move(direction) => self.player_move(direction),
+				Input::Shoot => self.player_shoot(),
+			}
+			self.dirty = true;
+		}
+
+		self.start_shake();
+		for coords in std::mem::take(&mut self.sim.cheese_eaten_at) {
+			let color = Color::new(1.0, 0.85, 0.2, 1.0);
+			self.particles.extend(Particle::burst(coords, color));
+		}
+		self.play_sound_effects(ctx);
+
+		Ok(())
+	}
+
+	fn mouse_motion_event(
+		&mut self,
+		_ctx: &mut Context,
+		x: f32,
+		y: f32,
+		_dx: f32,
+		_dy: f32,
+	) -> GameResult {
+		self.mouse_position = Vec2::new(x, y);
+		if self.show_debug {
+			self.dirty = true;
+		}
+		Ok(())
+	}
+
+	fn resize_event(&mut self, _ctx: &mut Context, width: f32, height: f32) -> GameResult {
+		self.window_size = Vec2::new(width, height);
+		self.dirty = true;
 		Ok(())
 	}
 
 	fn key_down_event(&mut self, ctx: &mut Context, input: KeyInput, _repeated: bool) -> GameResult {
-		let can_play = self.rays.is_empty();
-		match input.keycode {
-			Some(VirtualKeyCode::Escape) => ctx.request_quit(),
-			Some(VirtualKeyCode::R) => {
-				self.rays = vec![];
-				self.grid = self.level.grid.clone();
-				self.cheese_count_got_here = 0;
-				self.step_count = self.step_count_at_level_start;
-				self.reset_count += 1;
-				let entry_coords = self.level.entry_coords;
-				let entry_direction = self.level.entry_direction;
-				self.grid.get_mut(entry_coords).unwrap().obj = Some(Obj::from_kind(ObjKind::Player));
-				self
-					.grid
-					.get_mut(entry_coords)
-					.unwrap()
-					.obj
-					.as_mut()
-					.unwrap()
-					.animation = Animation::CommingFrom {
-					src: (IVec2::from(entry_coords) - entry_direction).into(),
-					time_start: Instant::now(),
-					duration: Duration::from_secs_f32(0.05),
-				};
+		self.dirty = true;
+		match self.state {
+			GameState::Menu { .. } => return self.menu_key_down(ctx, input),
+			GameState::Paused { .. } => return self.pause_key_down(ctx, input),
+			GameState::Dying { .. } | GameState::LevelTransition { .. } => return Ok(()),
+			GameState::Playing => {},
+		}
+		self.objective_banner = None;
+		let can_play = self.sim.is_settled();
+		let can_shoot = can_play && !self.won;
+		let action = input.keycode.and_then(|key| self.keybindings.action(key));
+		// The player taking over stops the replay.
+		if action.is_some() {
+			self.replay_inputs.clear();
+		}
+		match action {
+			Some(Action::Move(direction)) => {
+				let can_queue = !self.won && !self.sim.is_out_of_steps();
+				if can_queue && self.input_queue.len() < Game::INPUT_QUEUE_CAPACITY {
+					self.input_queue.push_back(direction);
+				}
 			},
-			Some(VirtualKeyCode::Up) if can_play => self.player_move(IVec2::from([0, -1])),
-			Some(VirtualKeyCode::Down) if can_play => self.player_move(IVec2::from([0, 1])),
-			Some(VirtualKeyCode::Left) if can_play => self.player_move(IVec2::from([-1, 0])),
-			Some(VirtualKeyCode::Right) if can_play => self.player_move(IVec2::from([1, 0])),
-			Some(VirtualKeyCode::Space) | Some(VirtualKeyCode::Return) if can_play => {
-				self.player_shoot()
+			Some(Action::Reset) => self.reset_level(),
+			Some(Action::Shoot) if can_shoot => self.player_shoot(),
+			Some(Action::Undo) if can_play => self.undo(),
+			Some(Action::Redo) if can_play => self.redo(),
+			Some(_) => {},
+			None => match input.keycode {
+				Some(VirtualKeyCode::Escape | VirtualKeyCode::P) => {
+					self.state =
+						GameState::Paused { selected: PauseOption::Resume, confirming_quit: false };
+					self.input_queue.clear();
+				},
+				Some(VirtualKeyCode::N) => self.next_note_page(),
+				Some(VirtualKeyCode::Tab) => self.show_stats = !self.show_stats,
+				Some(VirtualKeyCode::F3) => self.show_debug = !self.show_debug,
+				Some(VirtualKeyCode::G) => self.show_grid_lines = !self.show_grid_lines,
+				Some(VirtualKeyCode::F5) => self.reload_level(),
+				Some(VirtualKeyCode::F9) => self.start_or_stop_recording(),
+				Some(VirtualKeyCode::F11) => self.toggle_fullscreen(ctx),
+				Some(VirtualKeyCode::M) => {
+					self.change_audio_settings(|settings| settings.muted = !settings.muted)
+				},
+				Some(VirtualKeyCode::Plus | VirtualKeyCode::Equals | VirtualKeyCode::NumpadAdd) => self
+					.change_audio_settings(|settings| {
+						settings.volume += AudioSettings::VOLUME_STEP;
+						settings.muted = false;
+					}),
+				Some(VirtualKeyCode::Minus | VirtualKeyCode::NumpadSubtract) => {
+					self.change_audio_settings(|settings| settings.volume -= AudioSettings::VOLUME_STEP)
+				},
+				_ => {},
 			},
-			_ => {},
 		}
 
 		Ok(())
 	}
 
 	fn draw(&mut self, ctx: &mut Context) -> GameResult {
+		// The frame being drawn is not rendered yet, so this captures the previous one.
+		self.record_frame(ctx);
+
+		// The last drawn frame is kept and presented again if we don't draw anything.
+		if !self.dirty {
+			return Ok(());
+		}
+		// Checked before drawing so that the frame in which all the animations end
+		// is drawn before we stop drawing.
+		let animating = self.is_animating();
+
 		let mut canvas = Canvas::from_frame(ctx, Color::BLACK);
 		canvas.set_sampler(graphics::Sampler::nearest_clamp());
+		// The grid is seen through the camera, the text on top of it is not.
+		// Both are scaled and centered to fit in the window.
+		let mut camera = self.camera();
+		if let Some(shake) = &self.shake {
+			camera += shake.offset();
+		}
+		let view_size = self.view_size();
+		let view_in_window = self.view_in_window();
+		let scale = view_in_window.w / view_size.x;
+		let window_size = self.window_size;
+		let screen_coordinates = |view_corner: Vec2| {
+			Rect::new(
+				view_corner.x - view_in_window.x / scale,
+				view_corner.y - view_in_window.y / scale,
+				window_size.x / scale,
+				window_size.y / scale,
+			)
+		};
+		canvas.set_scissor_rect(view_in_window)?;
 
-		for ray in self.rays.iter() {
-			let center = if let Some(RaysAnimation { time_start, duration }) = self.rays_animation {
-				let dst = IVec2::from(ray.coords) + ray.direction;
-				let center_src = tile_rect(ray.coords).center();
-				let center_dst = tile_rect(dst.into()).center();
-				let progress = time_start.elapsed().as_secs_f32() / duration.as_secs_f32();
-				let progress = progress.clamp(0.0, 1.0);
-				let window_x = lerp(progress, center_src.x, center_dst.x);
-				let window_y = lerp(progress, center_src.y, center_dst.y);
-				Point2::from([window_x, window_y])
-			} else {
-				tile_rect(ray.coords).center()
+		if let GameState::Menu { .. } = self.state {
+			canvas.set_screen_coordinates(screen_coordinates(Vec2::ZERO));
+			self.draw_menu(ctx, &mut canvas)?;
+			canvas.finish(ctx)?;
+			self.dirty = false;
+			return Ok(());
+		}
+
+		canvas.set_screen_coordinates(screen_coordinates(camera));
+
+		// Thrown axes are drawn with the objects.
+		let mut flying_axes = vec![];
+		for ray in self.sim.rays.iter() {
+			// The beam goes from where the ray was shot to its head, that travels to the next tile.
+			let progress = match self.rays_animation {
+				Some(RaysAnimation { time_start, duration }) => {
+					(time_start.elapsed().as_secs_f32() / duration.as_secs_f32()).clamp(0.0, 1.0)
+				},
+				None => 0.0,
 			};
-			let a = Vec2::from(center) + ray.direction.as_vec2() * 0.5 * Vec2::new(Tile::W, Tile::H);
-			let b = Vec2::from(center) - ray.direction.as_vec2() * 0.5 * Vec2::new(Tile::W, Tile::H);
+			let center_head_src = Vec2::from(tile_rect(ray.coords).center());
+			let head =
+				center_head_src + ray.direction.as_vec2() * progress * Vec2::new(Tile::W, Tile::H);
+			if matches!(ray.action, RayAction::ThrowAxe) {
+				// Spinning a quarter turn per tile.
+				let rect = Rect::new(
+					head.x - Tile::W / 2.0,
+					head.y - Tile::H / 2.0,
+					Tile::W,
+					Tile::H,
+				);
+				flying_axes.push((rect, -(ray.step_count as f32 + progress)));
+				continue;
+			}
+			// Wrapping around the grid breaks the beam into several lines.
+			let mut lines = vec![vec![]];
+			let tiles = ray.path.iter().chain([&ray.coords]);
+			let mut previous_coords: Option<Point2<i32>> = None;
+			for coords in tiles {
+				let is_adjacent = previous_coords.is_none_or(|previous_coords| {
+					let step = IVec2::from(*coords) - IVec2::from(previous_coords);
+					step.x.abs() + step.y.abs() <= 1
+				});
+				if !is_adjacent {
+					lines.push(vec![]);
+				}
+				lines
+					.last_mut()
+					.unwrap()
+					.push(Vec2::from(tile_rect(*coords).center()));
+				previous_coords = Some(*coords);
+			}
+			if progress > 0.0 {
+				lines.last_mut().unwrap().push(head);
+			}
 			let raygun_kind = match ray.action {
 				RayAction::SwapWith { .. } => RaygunKind::SwapWithShooter,
 				RayAction::Duplicate => RaygunKind::DuplicateShootee,
@@ -1355,86 +6053,89 @@ impl EventHandler for Game {
 					RaygunKind::TurnInto(Box::new(into_what.clone()))
 				},
 				RayAction::TurnIntoTurnInto => RaygunKind::TurnIntoTurnInto,
+				RayAction::Delete => RaygunKind::Delete,
+				RayAction::Push => RaygunKind::Push,
+				RayAction::ThrowAxe => unreachable!(),
 			};
-			let color = raygun_kind.color();
-			canvas.draw(
-				&graphics::Mesh::new_line(ctx, &[a, b], 10.0, color)?,
-				DrawParam::default().z(4),
-			);
-		}
-
-		for grid_y in 0..Grid::H {
-			for grid_x in 0..Grid::W {
-				let coords = Point2::from([grid_x, grid_y]);
-
-				if matches!(
-					self
-						.grid
-						.get(Point2::from([grid_x, grid_y]))
-						.unwrap()
-						.ground,
-					Ground::Ice
-				) {
-					draw_sprite(
-						Sprite::Ice,
-						tile_rect(coords),
-						1,
-						Color::WHITE,
-						0.0,
-						&mut canvas,
-						&self.spritesheet,
-					);
-				} else {
-					draw_sprite(
-						Sprite::Grass,
-						tile_rect(coords),
-						1,
-						Color::WHITE,
-						0.0,
-						&mut canvas,
-						&self.spritesheet,
-					);
-					if matches!(
-						self
-							.grid
-							.get(Point2::from([grid_x, grid_y]))
-							.unwrap()
-							.ground,
-						Ground::Sapling { .. }
-					) {
-						draw_sprite(
-							Sprite::Sapling,
-							tile_rect(coords),
-							2,
-							Color::WHITE,
-							0.0,
-							&mut canvas,
-							&self.spritesheet,
-						);
-					}
-				}
+			let color = raygun_kind.color();
+			for line in lines.iter().filter(|line| line.len() >= 2) {
+				canvas.draw(
+					&graphics::Mesh::new_line(ctx, line, 10.0, color)?,
+					DrawParam::default().z(4),
+				);
+			}
+		}
 
-				if let Some(exit) = &self.grid.get(Point2::from([grid_x, grid_y])).unwrap().exit {
-					let rotation = match exit.direction {
-						IVec2 { x: 1, y: 0 } => 0.0,
-						IVec2 { x: 0, y: -1 } => 1.0,
-						IVec2 { x: -1, y: 0 } => 2.0,
-						IVec2 { x: 0, y: 1 } => 3.0,
-						_ => panic!(),
-					};
-					draw_sprite(
-						Sprite::Arrow,
-						tile_rect(coords),
-						2,
-						Color::new(0.8, 0.8, 0.8, 1.0),
-						rotation,
-						&mut canvas,
-						&self.spritesheet,
-					);
+		if self.show_trail {
+			let segment_count = self.trail.len().saturating_sub(1);
+			for (segment_index, (src, dst)) in
+				self.trail.iter().zip(self.trail.iter().skip(1)).enumerate()
+			{
+				// Only adjacent positions are linked, no lines across the grid
+				// when the player wraps around or gets swapped away.
+				let step = IVec2::from(*dst) - IVec2::from(*src);
+				if step.x.abs() + step.y.abs() != 1 {
+					continue;
 				}
+				let alpha = (segment_index + 1) as f32 / segment_count as f32 * 0.5;
+				let a = Vec2::from(tile_rect(*src).center());
+				let b = Vec2::from(tile_rect(*dst).center());
+				canvas.draw(
+					&graphics::Mesh::new_line(ctx, &[a, b], 8.0, Color::new(1.0, 1.0, 1.0, alpha))?,
+					DrawParam::default().z(2),
+				);
+			}
+		}
+
+		if !self
+			.ground_layer
+			.as_ref()
+			.is_some_and(|ground_layer| ground_layer.is_up_to_date(&self.sim.grid))
+		{
+			self.ground_layer = Some(GroundLayer::new(ctx, &self.sim.grid, &self.spritesheet));
+		}
+		canvas.draw(
+			&self.ground_layer.as_ref().unwrap().instances,
+			DrawParam::default().z(1),
+		);
+
+		// Objects are all drawn in one go, the instances are ordered by their own z.
+		self.object_instances.clear();
+		let sprite_size = Sprite::size_in(&self.spritesheet);
+		for (rect, rotation) in flying_axes {
+			self.object_instances.push(sprite_draw_param(
+				Sprite::Axe,
+				sprite_size,
+				rect,
+				3,
+				Color::WHITE,
+				rotation,
+				false,
+			));
+		}
+		// Where the tagged objects are drawn, for the notes that follow them.
+		let mut tagged_rects: HashMap<&str, Rect> = HashMap::new();
+		// Outlined so that the player can tell what shooting would fire.
+		let rayguns_in_reach = self.sim.rayguns_in_reach();
+		for grid_y in 0..self.sim.grid.h {
+			for grid_x in 0..self.sim.grid.w {
+				let coords = Point2::from([grid_x, grid_y]);
 
-				if let Some(obj) = &self.grid.get(Point2::from([grid_x, grid_y])).unwrap().obj {
+				if let Some(obj) = &self
+					.sim
+					.grid
+					.get(Point2::from([grid_x, grid_y]))
+					.unwrap()
+					.obj
+				{
 					let (sprite, color) = obj.kind.sprite_and_color();
+					let sprite = match (obj.animation.moving_progress(), sprite.walk_frames()) {
+						(Some(progress), frames) if !frames.is_empty() => {
+							let frame_index = (progress * frames.len() as f32) as usize;
+							frames[frame_index.min(frames.len() - 1)]
+						},
+						_ => sprite,
+					};
 					let rect = match obj.animation {
 						Animation::None => tile_rect(coords),
 						Animation::CommingFrom { src, time_start, duration } => {
@@ -1465,7 +6166,69 @@ impl EventHandler for Game {
 							Rect::new(window_x, window_y, dst_rect.w, dst_rect.h)
 						},
 					};
-					draw_sprite(sprite, rect, 3, color, 0.0, &mut canvas, &self.spritesheet);
+					// Lit bombs throb until they explode.
+					let rect = if self.sim.lit_bombs.contains(&coords) {
+						let time = self
+							.fuse_start
+							.map_or(0.0, |start| start.elapsed().as_secs_f32());
+						let grow = 1.0 + 0.15 * (time * 40.0).sin().abs();
+						let center = rect.center();
+						let (w, h) = (rect.w * grow, rect.h * grow);
+						Rect::new(center.x - w / 2.0, center.y - h / 2.0, w, h)
+					} else {
+						rect
+					};
+					if let Some(tag) = &obj.tag {
+						tagged_rects.entry(tag).or_insert(rect);
+					}
+					let rotation = match obj.kind {
+						ObjKind::OneWay { direction } => direction_rotation(direction).unwrap_or(0.0),
+						_ => 0.0,
+					};
+					let flip_x = match obj.kind {
+						ObjKind::Player => self.player_facing_left,
+						ObjKind::Glass { turn } => turn == Turn::Right,
+						_ => false,
+					};
+					self.object_instances.push(sprite_draw_param(
+						sprite,
+						sprite_size,
+						rect,
+						3,
+						color,
+						rotation,
+						flip_x,
+					));
+
+					// A dot on the side the player faces, that is where it shoots.
+					if obj.kind == ObjKind::Player && !self.sim.omnidirectional {
+						let dot_center = Vec2::from(rect.center())
+							+ self.sim.facing.as_vec2() * 0.4 * Vec2::new(Tile::W, Tile::H);
+						canvas.draw(
+							&graphics::Mesh::new_circle(
+								ctx,
+								graphics::DrawMode::fill(),
+								dot_center,
+								Tile::W * 0.06,
+								0.5,
+								Color::new(0.1, 0.1, 0.1, 1.0),
+							)?,
+							DrawParam::default().z(4),
+						);
+					}
+
+					if rayguns_in_reach.contains(&coords) {
+						canvas.draw(
+							&graphics::Mesh::new_rounded_rectangle(
+								ctx,
+								graphics::DrawMode::stroke(4.0),
+								Rect::new(rect.x + 2.0, rect.y + 2.0, rect.w - 4.0, rect.h - 4.0),
+								8.0,
+								color,
+							)?,
+							DrawParam::default().z(4),
+						);
+					}
 
 					// TurnInto rayguns display what they turn their targets into on them.
 					// This is kinda recursive is they can turn targets into TurnInto guns etc.
@@ -1473,57 +6236,57 @@ impl EventHandler for Game {
 						let size = 4.0 * 8.0;
 						let sub_rect = Rect::new(rect.right() - size, rect.bottom() - size, size, size);
 						let (sprite, color) = into_what.sprite_and_color();
-						draw_sprite(
+						self.object_instances.push(sprite_draw_param(
 							sprite,
+							sprite_size,
 							sub_rect,
 							4,
 							color,
 							0.0,
-							&mut canvas,
-							&self.spritesheet,
-						);
+							false,
+						));
 						if let ObjKind::Raygun(RaygunKind::TurnInto(into_what)) = &**into_what {
 							let size = 2.0 * 8.0;
 							let sub_rect =
 								Rect::new(rect.right() - size, rect.bottom() - size, size, size);
 							let (sprite, color) = into_what.sprite_and_color();
-							draw_sprite(
+							self.object_instances.push(sprite_draw_param(
 								sprite,
+								sprite_size,
 								sub_rect,
 								5,
 								color,
 								0.0,
-								&mut canvas,
-								&self.spritesheet,
-							);
+								false,
+							));
 							if let ObjKind::Raygun(RaygunKind::TurnInto(into_what)) = &**into_what {
 								let size = 1.0 * 8.0;
 								let sub_rect =
 									Rect::new(rect.right() - size, rect.bottom() - size, size, size);
 								let (sprite, color) = into_what.sprite_and_color();
-								draw_sprite(
+								self.object_instances.push(sprite_draw_param(
 									sprite,
+									sprite_size,
 									sub_rect,
 									6,
 									color,
 									0.0,
-									&mut canvas,
-									&self.spritesheet,
-								);
+									false,
+								));
 								if let ObjKind::Raygun(RaygunKind::TurnInto(into_what)) = &**into_what {
 									let size = 0.5 * 8.0;
 									let sub_rect =
 										Rect::new(rect.right() - size, rect.bottom() - size, size, size);
 									let (sprite, color) = into_what.sprite_and_color();
-									draw_sprite(
+									self.object_instances.push(sprite_draw_param(
 										sprite,
+										sprite_size,
 										sub_rect,
 										7,
 										color,
 										0.0,
-										&mut canvas,
-										&self.spritesheet,
-									);
+										false,
+									));
 								}
 							}
 						}
@@ -1532,24 +6295,137 @@ impl EventHandler for Game {
 			}
 		}
 
+		if let Some(ghost) = &self.ghost {
+			for grid_y in 0..ghost.sim.grid.h {
+				for grid_x in 0..ghost.sim.grid.w {
+					let coords = Point2::from([grid_x, grid_y]);
+					let tile = ghost.sim.grid.get(coords).unwrap();
+					if tile
+						.obj
+						.as_ref()
+						.is_some_and(|obj| obj.kind == ObjKind::Player)
+					{
+						let (sprite, color) = ObjKind::Player.sprite_and_color();
+						let color = Color::new(color.r, color.g, color.b, 0.35);
+						self.object_instances.push(sprite_draw_param(
+							sprite,
+							sprite_size,
+							tile_rect(coords),
+							4,
+							color,
+							0.0,
+							false,
+						));
+					}
+				}
+			}
+		}
+
+		canvas.draw(&self.object_instances, DrawParam::default().z(3));
+
+		for particle in self.particles.iter() {
+			let progress =
+				particle.time_start.elapsed().as_secs_f32() / Particle::LIFETIME.as_secs_f32();
+			let mut color = particle.color;
+			color.a = (1.0 - progress).clamp(0.0, 1.0);
+			let size = Tile::W * 0.08;
+			canvas.draw(
+				&graphics::Mesh::new_rectangle(
+					ctx,
+					graphics::DrawMode::fill(),
+					Rect::new(
+						particle.position.x - size / 2.0,
+						particle.position.y - size / 2.0,
+						size,
+						size,
+					),
+					color,
+				)?,
+				DrawParam::default().z(7),
+			);
+		}
+
+		for blast in self.blasts.iter() {
+			let progress = blast.time_start.elapsed().as_secs_f32() / Blast::DURATION.as_secs_f32();
+			let alpha = (1.0 - progress).clamp(0.0, 1.0);
+			let center = Vec2::from(tile_rect(blast.center).center());
+			let size = Vec2::new(Tile::W, Tile::H) * (2.0 + progress);
+			canvas.draw(
+				&graphics::Mesh::new_rectangle(
+					ctx,
+					graphics::DrawMode::fill(),
+					Rect::new(
+						center.x - size.x / 2.0,
+						center.y - size.y / 2.0,
+						size.x,
+						size.y,
+					),
+					Color::new(1.0, 0.6, 0.1, alpha * 0.8),
+				)?,
+				DrawParam::default().z(7),
+			);
+		}
+
 		for note in self.notes.iter() {
+			if note.page.is_some_and(|page| page != self.note_page) {
+				continue;
+			}
 			let mut text = graphics::Text::new(&note.text);
 			text.set_scale(note.scale);
-			let offset = Vec2::from([note.coords.x as f32, note.coords.y as f32])
+			if let Some(width) = note.width {
+				text.set_bounds(Vec2::new(width, f32::INFINITY));
+				text.set_wrap(true);
+			}
+			let mut offset = Vec2::from([note.coords.x as f32, note.coords.y as f32])
 				* Vec2::from([Tile::W, Tile::H]);
+			if let Some(anchor) = &note.anchor {
+				let Some(rect) = tagged_rects.get(anchor.as_str()) else {
+					continue;
+				};
+				offset += Vec2::new(rect.x, rect.y);
+			}
 			let z = match note.depth {
 				NoteDepth::Front => 3,
 				NoteDepth::Back => 2,
 			};
+			if note.has_box {
+				// Drawn before the text at the same depth so that it is just below it.
+				// Light text gets a dark box and dark text a light box.
+				let size = text.measure(ctx)?;
+				let margin = note.scale * 0.25;
+				let brightness = (note.color.r + note.color.g + note.color.b) / 3.0;
+				let box_color = if brightness > 0.5 {
+					Color::new(0.0, 0.0, 0.0, 0.6)
+				} else {
+					Color::new(1.0, 1.0, 1.0, 0.6)
+				};
+				canvas.draw(
+					&graphics::Mesh::new_rounded_rectangle(
+						ctx,
+						graphics::DrawMode::fill(),
+						Rect::new(
+							offset.x - margin,
+							offset.y - margin,
+							size.x + margin * 2.0,
+							size.y + margin * 2.0,
+						),
+						margin,
+						box_color,
+					)?,
+					DrawParam::default().z(z),
+				);
+			}
 			canvas.draw(
 				&text,
-				DrawParam::default()
-					.z(z)
-					.color(Color::BLACK)
-					.offset(-offset),
+				DrawParam::default().z(z).color(note.color).offset(-offset),
 			);
 		}
 
+		if self.show_grid_lines {
+			self.draw_grid_lines(ctx, &mut canvas)?;
+		}
+
+		canvas.set_screen_coordinates(screen_coordinates(Vec2::ZERO));
 		let mut text_y = 0.0;
 		{
 			let mut text = graphics::Text::new(&self.level.name);
@@ -1565,16 +6441,16 @@ impl EventHandler for Game {
 			text_y += scale;
 		}
 
-		if self.cheese_count + self.cheese_count_got_here >= 1 {
+		if self.cheese_count + self.sim.cheese_count_got_here >= 1 {
 			let cheese_text = format!(
 				"{}{} cheese{}",
 				self.cheese_count,
-				if self.cheese_count_got_here >= 1 {
-					format!("+{}?", self.cheese_count_got_here)
+				if self.sim.cheese_count_got_here >= 1 {
+					format!("+{}?", self.sim.cheese_count_got_here)
 				} else {
 					"".to_string()
 				},
-				if self.cheese_count + self.cheese_count_got_here >= 2 {
+				if self.cheese_count + self.sim.cheese_count_got_here >= 2 {
 					"s"
 				} else {
 					""
@@ -1594,7 +6470,38 @@ impl EventHandler for Game {
 		}
 
 		{
-			let mut text = graphics::Text::new(&format!(" {} steps", self.step_count));
+			let best_steps_text = match self.best_steps.get(&self.level.id) {
+				Some(best) => format!(" (best: {best} steps)"),
+				None => "".to_string(),
+			};
+			let steps_left_text = match self.sim.step_limit {
+				Some(step_limit) => {
+					format!(", {} left", step_limit.saturating_sub(self.sim.step_count))
+				},
+				None => "".to_string(),
+			};
+			let step_count = self.step_count_at_level_start + self.sim.step_count;
+			let mut text = graphics::Text::new(format!(
+				" {step_count} steps{steps_left_text}{best_steps_text}"
+			));
+			let scale = 20.0;
+			text.set_scale(scale);
+			canvas.draw(
+				&text,
+				DrawParam::default()
+					.z(8)
+					.color(Color::BLACK)
+					.offset(-Vec2::from([0.0, text_y])),
+			);
+			text_y += scale;
+		}
+
+		if self.death_count >= 1 {
+			let mut text = graphics::Text::new(format!(
+				" {} death{}",
+				self.death_count,
+				if self.death_count >= 2 { "s" } else { "" }
+			));
 			let scale = 20.0;
 			text.set_scale(scale);
 			canvas.draw(
@@ -1621,19 +6528,1042 @@ impl EventHandler for Game {
 			text_y += scale;
 		}
 
+		let warnings = self.levels.warnings.iter().chain(self.warnings.iter());
+		for warning in warnings.chain(self.reload_warnings.iter()) {
+			let mut text = graphics::Text::new(warning);
+			let scale = 20.0;
+			text.set_scale(scale);
+			canvas.draw(
+				&text,
+				DrawParam::default()
+					.z(8)
+					.color(Color::new(0.6, 0.3, 0.0, 1.0))
+					.offset(-Vec2::from([0.0, text_y])),
+			);
+			text_y += scale;
+		}
+
+		if let Some(Banner { text, time_start, duration }) = &self.objective_banner {
+			let progress = time_start.elapsed().as_secs_f32() / duration.as_secs_f32();
+			if progress < 1.0 {
+				// Fades in quickly and then fades out slowly.
+				let alpha = (progress / 0.1).min((1.0 - progress) / 0.3).min(1.0);
+				let mut text = graphics::Text::new(text);
+				text.set_scale(40.0);
+				text.set_layout(graphics::TextLayout::center());
+				let center = view_size / 2.0;
+				canvas.draw(
+					&text,
+					DrawParam::default()
+						.z(9)
+						.color(Color::new(0.0, 0.0, 0.0, alpha))
+						.dest(center),
+				);
+			}
+		}
+
+		if self.won {
+			let mut text = graphics::Text::new("Level complete!");
+			text.set_scale(60.0);
+			text.set_layout(graphics::TextLayout::center());
+			let center = view_size / 2.0;
+			canvas.draw(
+				&text,
+				DrawParam::default()
+					.z(9)
+					.color(Color::new(1.0, 0.9, 0.2, 1.0))
+					.dest(center),
+			);
+		}
+
+		if !self.won && self.sim.is_out_of_steps() && self.sim.is_settled() {
+			let mut text = graphics::Text::new("Out of moves — press R");
+			text.set_scale(50.0);
+			text.set_layout(graphics::TextLayout::center());
+			let center = view_size / 2.0;
+			canvas.draw(
+				&text,
+				DrawParam::default()
+					.z(9)
+					.color(Color::new(0.8, 0.1, 0.1, 1.0))
+					.dest(center),
+			);
+		}
+
+		if let GameState::Dying { time_start } = self.state {
+			// The board flashes red and fades back as the attempt is about to restart.
+			let progress = time_start.elapsed().as_secs_f32() / Game::DEATH_DURATION.as_secs_f32();
+			let alpha = (1.0 - progress).clamp(0.0, 1.0) * 0.6;
+			canvas.draw(
+				&graphics::Mesh::new_rectangle(
+					ctx,
+					graphics::DrawMode::fill(),
+					Rect::new(0.0, 0.0, view_size.x, view_size.y),
+					Color::new(0.8, 0.0, 0.0, alpha),
+				)?,
+				DrawParam::default().z(10),
+			);
+			let mut text = graphics::Text::new("You died");
+			text.set_scale(60.0);
+			text.set_layout(graphics::TextLayout::center());
+			canvas.draw(
+				&text,
+				DrawParam::default()
+					.z(11)
+					.color(Color::WHITE)
+					.dest(view_size / 2.0),
+			);
+		}
+
+		if let GameState::LevelTransition { time_start, .. } = self.state {
+			let progress =
+				time_start.elapsed().as_secs_f32() / Game::LEVEL_TRANSITION_DURATION.as_secs_f32();
+			let alpha = 1.0 - (progress * 2.0 - 1.0).abs().min(1.0);
+			canvas.draw(
+				&graphics::Mesh::new_rectangle(
+					ctx,
+					graphics::DrawMode::fill(),
+					Rect::new(0.0, 0.0, view_size.x, view_size.y),
+					Color::new(0.0, 0.0, 0.0, alpha),
+				)?,
+				DrawParam::default().z(12),
+			);
+		}
+
+		if self.show_stats {
+			self.draw_stats(ctx, &mut canvas)?;
+		}
+		if self.show_debug {
+			let mut text = graphics::Text::new(self.debug_text());
+			let scale = 20.0;
+			text.set_scale(scale);
+			canvas.draw(
+				&graphics::Mesh::new_rectangle(
+					ctx,
+					graphics::DrawMode::fill(),
+					Rect::new(0.0, view_size.y - scale, view_size.x, scale),
+					Color::new(0.0, 0.0, 0.0, 0.6),
+				)?,
+				DrawParam::default().z(10),
+			);
+			canvas.draw(
+				&text,
+				DrawParam::default()
+					.z(11)
+					.color(Color::WHITE)
+					.dest(Vec2::new(0.0, view_size.y - scale)),
+			);
+		}
+		self.draw_pause_menu(ctx, &mut canvas)?;
+
 		canvas.finish(ctx)?;
+		self.dirty = animating;
 		Ok(())
 	}
 }
 
 fn main() -> GameResult {
-	let (mut ctx, event_loop) = ContextBuilder::new("Puzh", "Anima :3")
+	let settings = CommandLineSettings::parse();
+	if settings.list_levels {
+		let mut levels = Levels::load(false);
+		for level in levels.all() {
+			let error_count = level.error_messages.len();
+			let errors = match error_count {
+				0 => "".to_string(),
+				1 => " (1 error)".to_string(),
+				_ => format!(" ({error_count} errors)"),
+			};
+			println!("{}\t{}{errors}", level.id, level.name);
+		}
+		return Ok(());
+	}
+	if settings.validate {
+		let mut levels = Levels::load(false);
+		let mut problem_count = 0;
+		for level in levels.all() {
+			let origin = match &level.path {
+				Some(path) => path.display().to_string(),
+				None => "built-in".to_string(),
+			};
+			if level.error_messages.is_empty() {
+				println!("level \"{}\" ({origin}): ok", level.id);
+			} else {
+				println!("level \"{}\" ({origin}):", level.id);
+				for error_message in level.error_messages.iter() {
+					println!("\t{error_message}");
+				}
+			}
+			problem_count += level.error_messages.len();
+		}
+		// Level files that could not be read and duplicate ids were printed as they were found.
+		problem_count += levels.warnings.len();
+		if problem_count == 0 {
+			println!("all levels are fine");
+			return Ok(());
+		} else {
+			println!("found {problem_count} problem(s)");
+			std::process::exit(1);
+		}
+	}
+	if let Some(level_id) = settings.export_level_id {
+		let mut levels = Levels::load(false);
+		let level = match levels.get(&level_id) {
+			Ok(level) => level,
+			Err(error) => {
+				println!("error: {error}");
+				std::process::exit(1);
+			},
+		};
+		match level.save_to_text() {
+			Some(text) => print!("{text}"),
+			None => {
+				println!("error: level \"{level_id}\" has a direction that cannot be written");
+				std::process::exit(1);
+			},
+		}
+		return Ok(());
+	}
+	if let Some(level_id) = settings.check_level_id {
+		let mut levels = Levels::load(false);
+		let level = match levels.get(&level_id) {
+			Ok(level) => level,
+			Err(error) => {
+				println!("error: {error}");
+				std::process::exit(1);
+			},
+		};
+		match solve(&level) {
+			Ok(move_count) => {
+				println!("level \"{level_id}\" can be completed in {move_count} moves");
+				return Ok(());
+			},
+			Err(error) => {
+				println!("level \"{level_id}\" {error}");
+				std::process::exit(1);
+			},
+		}
+	}
+
+	let context_builder = ContextBuilder::new("Puzh", "Anima :3")
 		.window_setup(WindowSetup::default().title("Puzh").vsync(true).srgb(false))
 		.window_mode(
-			WindowMode::default().dimensions(Grid::W as f32 * Tile::W, Grid::H as f32 * Tile::H),
-		)
-		.build()
-		.unwrap();
-	let game = Game::new(&mut ctx)?;
+			WindowMode::default()
+				.dimensions(
+					Grid::DEFAULT_W as f32 * Tile::W,
+					Grid::DEFAULT_H as f32 * Tile::H,
+				)
+				.resizable(true),
+		);
+	// ggez opens the audio output when building the context and fails if there is none,
+	// before the game gets a chance to go on without sounds.
+	let (mut ctx, event_loop) = match context_builder.build() {
+		Ok(built) => built,
+		Err(GameError::AudioError(error)) => {
+			println!("error: no audio output device could be opened, the game needs one: {error}");
+			std::process::exit(1);
+		},
+		Err(error) => {
+			println!("error: failed to start the game: {error}");
+			std::process::exit(1);
+		},
+	};
+	let game = Game::new(&mut ctx, settings)?;
 	event::run(ctx, event_loop, game);
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const RIGHT: IVec2 = IVec2::new(1, 0);
+	const LEFT: IVec2 = IVec2::new(-1, 0);
+	const UP: IVec2 = IVec2::new(0, -1);
+	const DOWN: IVec2 = IVec2::new(0, 1);
+
+	/// Parses a level that is expected to have no errors.
+	fn level(text: &str) -> Level {
+		let level = Level::load_from_text(text, "test_level");
+		assert!(
+			level.error_messages.is_empty(),
+			"{:?}",
+			level.error_messages
+		);
+		level
+	}
+
+	/// The objects of the grid, a string per row written like grid art, with `@` for players,
+	/// `r` rocks, `#` walls, `o` ropes, `s` soap, `b` balloons, `n` bunnies, `m` mirrors,
+	/// `h` walls with holes, `c` cheese, `g` glass, `x` rayguns, `t` teleporters
+	/// and `?` anything else.
+	fn rows(grid: &Grid) -> Vec<String> {
+		(0..grid.h)
+			.map(|grid_y| {
+				let row: Vec<String> = (0..grid.w)
+					.map(|grid_x| {
+						let tile = grid.get(Point2::from([grid_x, grid_y])).unwrap();
+						let character = match tile.obj.as_ref().map(|obj| &obj.kind) {
+							None => '.',
+							Some(ObjKind::Player) => '@',
+							Some(ObjKind::Rock) => 'r',
+							Some(ObjKind::Wall) => '#',
+							Some(ObjKind::Rope) => 'o',
+							Some(ObjKind::Soap) => 's',
+							Some(ObjKind::Balloon) => 'b',
+							Some(ObjKind::Bunny) => 'n',
+							Some(ObjKind::Mirror) => 'm',
+							Some(ObjKind::WallWithHoles) => 'h',
+							Some(ObjKind::Cheese) => 'c',
+							Some(ObjKind::Glass { .. }) => 'g',
+							Some(ObjKind::Raygun(_)) => 'x',
+							Some(ObjKind::Teleporter { .. }) => 't',
+							Some(_) => '?',
+						};
+						character.to_string()
+					})
+					.collect();
+				row.join(" ")
+			})
+			.collect()
+	}
+
+	/// Advances the rays until there are none left, failing if they go on for too long.
+	fn advance_rays_until_gone(sim: &mut Simulation) {
+		for _ in 0..1000 {
+			if sim.rays.is_empty() {
+				return;
+			}
+			sim.advance_rays();
+		}
+		panic!("the rays are still going");
+	}
+
+	#[test]
+	fn player_pushes_rock_without_window() {
+		let level = level(
+			"id push\n\
+			size 4 1\n\
+			grid\n\
+			@ r . .\n\
+			entry @ right\n\
+			obj r rock\n",
+		);
+		let mut sim = Simulation::new(&level);
+		assert_eq!(rows(&sim.grid), ["@ r . ."]);
+		sim.player_move(RIGHT);
+		assert_eq!(rows(&sim.grid), [". @ r ."]);
+		sim.player_move(LEFT);
+		assert_eq!(rows(&sim.grid), ["@ . r ."]);
+		// The edges of the grid block like walls.
+		sim.player_move(UP);
+		sim.player_move(DOWN);
+		sim.player_move(LEFT);
+		assert_eq!(rows(&sim.grid), ["@ . r ."]);
+	}
+
+	#[test]
+	fn wrapping_grid_coords() {
+		let mut grid = Level::load_from_text("id wrap\nsize 4 3\nwrap\n", "wrap").grid;
+		assert!(grid.wrap);
+		assert_eq!(
+			grid.index(Point2::from([-1, 0])),
+			grid.index(Point2::from([grid.w - 1, 0]))
+		);
+		assert_eq!(
+			grid.index(Point2::from([0, grid.h])),
+			grid.index(Point2::from([0, 0]))
+		);
+		// A row full of objects is a ring that cannot be pushed, a row with a gap is not.
+		for grid_x in 0..grid.w {
+			grid.get_mut(Point2::from([grid_x, 0])).unwrap().obj = Some(Obj::from_kind(ObjKind::Rock));
+		}
+		assert!(grid.is_line_full(Point2::from([3, 0]), LEFT));
+		grid.get_mut(Point2::from([1, 0])).unwrap().obj = None;
+		assert!(!grid.is_line_full(Point2::from([3, 0]), RIGHT));
+		// Without wrapping, coords out of the grid stay out.
+		assert_eq!(Grid::new(4, 3).index(Point2::from([-1, 0])), None);
+	}
+
+	#[test]
+	fn wrapping_moves_and_pushes() {
+		let level = level(
+			"id wrap\n\
+			size 4 3\n\
+			wrap\n\
+			grid\n\
+			@ . . .\n\
+			. . . r\n\
+			. . . .\n\
+			entry @ right\n\
+			obj r rock\n",
+		);
+		let mut sim = Simulation::new(&level);
+		sim.player_move(LEFT);
+		assert_eq!(rows(&sim.grid), [". . . @", ". . . r", ". . . ."]);
+		sim.player_move(DOWN);
+		assert_eq!(rows(&sim.grid), [". . . .", ". . . @", ". . . r"]);
+		// The rock is pushed off the bottom edge and comes back at the top.
+		sim.player_move(DOWN);
+		assert_eq!(rows(&sim.grid), [". . . r", ". . . .", ". . . @"]);
+		// Going off the top edge comes back at the bottom.
+		let mut sim = Simulation::new(&level);
+		sim.player_move(LEFT);
+		sim.player_move(UP);
+		assert_eq!(rows(&sim.grid), [". . . .", ". . . r", ". . . @"]);
+	}
+
+	#[test]
+	fn wrapping_rays() {
+		let level = level(
+			"id wrap_rays\n\
+			size 4 1\n\
+			wrap\n\
+			grid\n\
+			r . @ x\n\
+			entry @ right\n\
+			obj r rock\n\
+			obj x raygun:swap\n",
+		);
+		let mut sim = Simulation::new(&level);
+		// The ray goes off the right edge and hits the rock on the left.
+		sim.player_shoot();
+		advance_rays_until_gone(&mut sim);
+		assert_eq!(rows(&sim.grid), ["@ . r x"]);
+	}
+
+	#[test]
+	fn balloon_directive() {
+		let text = "id balloon\nsize 2 1\ngrid\n. b\nobj b balloon\n";
+		let level = level(text);
+		let tile = level.grid.get(Point2::from([1, 0])).unwrap();
+		assert!(tile
+			.obj
+			.as_ref()
+			.is_some_and(|obj| obj.kind == ObjKind::Balloon));
+	}
+
+	#[test]
+	fn balloon_only_pushed_by_players() {
+		let level = level(
+			"id balloon\n\
+			size 4 2\n\
+			grid\n\
+			@ b . .\n\
+			q r b .\n\
+			entry @ right\n\
+			obj q player\n\
+			obj r rock\n\
+			obj b balloon\n",
+		);
+		let mut sim = Simulation::new(&level);
+		// The rock that the second player pushes is blocked by the balloon.
+		sim.player_move(RIGHT);
+		assert_eq!(rows(&sim.grid), [". @ b .", "@ r b ."]);
+	}
+
+	#[test]
+	fn entry_at_coords() {
+		let level = level(
+			"id entry_at\n\
+			size 4 3\n\
+			grid\n\
+			. . . .\n\
+			. . . .\n\
+			. . . .\n\
+			entry_at 2 1 down\n",
+		);
+		assert_eq!((level.entry_coords.x, level.entry_coords.y), (2, 1));
+		assert_eq!(level.entry_direction, DOWN);
+		let sim = Simulation::new(&level);
+		assert_eq!(rows(&sim.grid), [". . . .", ". . @ .", ". . . ."]);
+
+		let level = Level::load_from_text("id entry_at\nsize 4 3\nentry_at 4 1 down\n", "entry_at");
+		assert!(level.error_messages[0].contains("out of the grid"));
+	}
+
+	#[test]
+	fn tile_with_two_exits() {
+		let level = level(
+			"id crossroads\n\
+			size 1 1\n\
+			grid\n\
+			e\n\
+			exit e left west\n\
+			exit e down south entrance\n",
+		);
+		let tile = level.grid.get(Point2::from([0, 0])).unwrap();
+		let dst_level_id = |direction| tile.exit(direction).map(|exit| exit.dst_level_id.as_str());
+		assert_eq!(dst_level_id(LEFT), Some("west"));
+		assert_eq!(dst_level_id(DOWN), Some("south"));
+		assert_eq!(dst_level_id(UP), None);
+		assert_eq!(dst_level_id(RIGHT), None);
+		// The player entering on the tile takes the exit of the direction it moves in.
+		let exited_to = |direction| {
+			Simulation::new(&level)
+				.player_move(direction)
+				.exited_to
+				.map(|exit| (exit.dst_level_id, exit.entry_name))
+		};
+		assert_eq!(exited_to(LEFT), Some(("west".to_string(), None)));
+		assert_eq!(
+			exited_to(DOWN),
+			Some(("south".to_string(), Some("entrance".to_string())))
+		);
+		assert_eq!(exited_to(UP), None);
+	}
+
+	#[test]
+	fn level_serde_round_trip() {
+		let level = Level::test();
+		let text = ron::to_string(&level).unwrap();
+		let level_back: Level = ron::from_str(&text).unwrap();
+		assert_eq!(ron::to_string(&level_back).unwrap(), text);
+		assert_eq!(rows(&level_back.grid), rows(&level.grid));
+	}
+
+	#[test]
+	fn save_to_text_round_trip() {
+		let levels = [
+			Level::test(),
+			level(
+				"name A few things\n\
+				id save\n\
+				size 5 3\n\
+				wrap\n\
+				tick_order column_major\n\
+				step_limit 30\n\
+				grid\n\
+				@ r . i i\n\
+				. c . w .\n\
+				. . k . e\n\
+				entry @ right\n\
+				obj r rock\n\
+				obj c cheese\n\
+				obj k oneway:up\n\
+				ground i ice\n\
+				ground w conv down\n\
+				exit e right elsewhere\n\
+				exit e down elsewhere back\n\
+				entry_at 3 0 left back\n\
+				note 1 1 20 front Hello;world\n\
+				note 0 2 15 back page 2 width 100 color purple box | box of rocks\n\
+				note 2 2 15 back | a | b\n",
+			),
+		];
+		for level in levels {
+			let text = level.save_to_text().unwrap();
+			let level_back = self::level(&text);
+			assert_eq!(level_back.save_to_text().unwrap(), text);
+			assert_eq!(rows(&level_back.grid), rows(&level.grid));
+		}
+	}
+
+	#[test]
+	fn direction_descr_round_trip() {
+		for direction in [RIGHT, UP, LEFT, DOWN] {
+			let descr = direction_descr(direction).unwrap();
+			assert_eq!(direction_from_descr(descr), Some(direction));
+			assert!(direction_rotation(direction).is_some());
+		}
+		assert_eq!(direction_descr(IVec2::new(1, 1)), None);
+		assert_eq!(direction_rotation(IVec2::new(0, 2)), None);
+	}
+
+	#[test]
+	fn ray_between_facing_mirrors_stops() {
+		let level = level(
+			"id mirrors\n\
+			size 4 2\n\
+			grid\n\
+			m . . m\n\
+			@ . . .\n\
+			entry @ right\n\
+			obj m mirror\n",
+		);
+		// No raygun can shoot between the mirrors, the ray is put there.
+		let mut sim = Simulation::new(&level);
+		sim.rays.push(Ray {
+			coords: Point2::from([1, 0]),
+			direction: RIGHT,
+			action: RayAction::Delete,
+			step_count: 0,
+			path: vec![],
+		});
+		let mut advance_count = 0;
+		while !sim.rays.is_empty() {
+			sim.advance_rays();
+			advance_count += 1;
+			assert!(advance_count <= Ray::MAX_STEP_COUNT + 1);
+		}
+		assert_eq!(rows(&sim.grid), ["m . . m", "@ . . ."]);
+	}
+
+	#[test]
+	fn ice_slides() {
+		// Each row is a level, `i` is ice, the player moves right once.
+		let cases = [
+			// Into a wall.
+			("@ i i # .", ". . @ # ."),
+			// Off the ice edge.
+			("@ i i . .", ". . . @ ."),
+			// Into another object, that is not pushed.
+			("@ i i r .", ". . @ r ."),
+			// A rock pushed onto the ice slides too.
+			("@ r i i .", ". @ . . r"),
+		];
+		for (row, expected_row) in cases {
+			let level = level(&format!(
+				"id ice\n\
+				size 5 1\n\
+				grid\n\
+				{row}\n\
+				entry @ right\n\
+				obj r rock\n\
+				obj # wall\n\
+				ground i ice\n"
+			));
+			let mut sim = Simulation::new(&level);
+			sim.player_move(RIGHT);
+			assert_eq!(rows(&sim.grid), [expected_row], "sliding in {row}");
+		}
+	}
+
+	#[test]
+	fn rope_chain_pulled() {
+		let level = level(
+			"id ropes\n\
+			size 6 1\n\
+			grid\n\
+			o o o o @ .\n\
+			entry @ right\n\
+			obj o rope\n",
+		);
+		let mut sim = Simulation::new(&level);
+		sim.player_move(RIGHT);
+		assert_eq!(rows(&sim.grid), [". o o o o @"]);
+	}
+
+	#[test]
+	fn soap_loop_does_not_crash() {
+		let level = level(
+			"id soap\n\
+			size 4 4\n\
+			wrap\n\
+			grid\n\
+			s s s s\n\
+			s @ . s\n\
+			s . . s\n\
+			s s s s\n\
+			entry @ right\n\
+			obj s soap\n",
+		);
+		let mut sim = Simulation::new(&level);
+		for direction in [RIGHT, RIGHT, DOWN, DOWN, LEFT, LEFT, LEFT, UP, UP, UP] {
+			sim.player_move(direction);
+			let soap_count = sim
+				.grid
+				.tiles
+				.iter()
+				.filter(|tile| {
+					tile
+						.obj
+						.as_ref()
+						.is_some_and(|obj| obj.kind == ObjKind::Soap)
+				})
+				.count();
+			assert_eq!(soap_count, 12);
+		}
+	}
+
+	#[test]
+	fn rock_fills_pit() {
+		let level = level(
+			"id pit\n\
+			size 4 1\n\
+			grid\n\
+			@ r u .\n\
+			entry @ right\n\
+			obj r rock\n\
+			ground u pit\n",
+		);
+		let mut sim = Simulation::new(&level);
+		sim.player_move(RIGHT);
+		assert_eq!(rows(&sim.grid), [". @ . ."]);
+		assert!(matches!(
+			sim.grid.get(Point2::from([2, 0])).unwrap().ground,
+			Ground::Grass
+		));
+		sim.player_move(RIGHT);
+		sim.player_move(RIGHT);
+		assert_eq!(rows(&sim.grid), [". . . @"]);
+		assert!(!sim.player_died);
+
+		// An unfilled pit is deadly.
+		let level = self::level("id pit\nsize 2 1\ngrid\n@ u\nentry @ right\nground u pit\n");
+		let mut sim = Simulation::new(&level);
+		sim.player_move(RIGHT);
+		assert_eq!(rows(&sim.grid), [". ."]);
+		assert!(sim.player_died);
+	}
+
+	#[test]
+	fn reset_after_partial_cheese_collection() {
+		let level = level(
+			"id cheese\n\
+			size 4 1\n\
+			grid\n\
+			@ c . c\n\
+			entry @ right\n\
+			obj c cheese\n\
+			goal collect_all_cheese\n",
+		);
+		let mut sim = Simulation::new(&level);
+		sim.player_move(RIGHT);
+		assert_eq!(sim.cheese_count_got_here, 1);
+		assert!(!sim.is_won());
+		// Resetting starts the attempt over the way `Game::restart_attempt` does,
+		// the cheese got in the abandoned attempt is back in the level and not counted.
+		let mut sim = Simulation::new_at_entry(&level, level.entry_coords, level.entry_direction);
+		assert_eq!(rows(&sim.grid), ["@ c . c"]);
+		assert_eq!(sim.cheese_count_got_here, 0);
+		for _ in 0..3 {
+			sim.player_move(RIGHT);
+		}
+		assert_eq!(sim.cheese_count_got_here, 2);
+		assert!(sim.is_won());
+	}
+
+	#[test]
+	fn glass_bends_ray_around_a_rectangle() {
+		let level = level(
+			"id glass\n\
+			size 6 4\n\
+			grid\n\
+			. . r . . .\n\
+			@ x . . g .\n\
+			. . . . . .\n\
+			. . g . g .\n\
+			entry @ right\n\
+			obj r rock\n\
+			obj x raygun:delete\n\
+			obj g glass:right\n",
+		);
+		let mut sim = Simulation::new(&level);
+		sim.player_shoot();
+		let mut trace = vec![];
+		while let Some(ray) = sim.rays.first() {
+			trace.push((ray.coords.x, ray.coords.y));
+			assert!(trace.len() < 100);
+			sim.advance_rays();
+		}
+		assert_eq!(
+			trace,
+			[
+				(1, 1),
+				(2, 1),
+				(3, 1),
+				(4, 1),
+				(4, 2),
+				(4, 3),
+				(3, 3),
+				(2, 3),
+				(2, 2),
+				(2, 1)
+			]
+		);
+		// The ray went around and got to the rock.
+		assert_eq!(
+			rows(&sim.grid),
+			[". . . . . .", "@ x . . g .", ". . . . . .", ". . g . g ."]
+		);
+	}
+
+	#[test]
+	fn two_players_push_whatever_the_tick_order() {
+		for tick_order in ["row_major", "column_major", "distance_from_input"] {
+			// The players are in a row and then in a column, pushing a rock in front of them.
+			let row_level = level(&format!(
+				"id tick_order\n\
+				size 5 5\n\
+				tick_order {tick_order}\n\
+				grid\n\
+				@ q r . .\n\
+				. . . . .\n\
+				. . . . .\n\
+				. . . . .\n\
+				. . . . .\n\
+				entry @ right\n\
+				obj q player\n\
+				obj r rock\n"
+			));
+			let mut sim = Simulation::new(&row_level);
+			sim.player_move(RIGHT);
+			assert_eq!(rows(&sim.grid)[0], ". @ @ r .", "with {tick_order}");
+			let column_level = level(&format!(
+				"id tick_order\n\
+				size 1 4\n\
+				tick_order {tick_order}\n\
+				grid\n\
+				@\n\
+				q\n\
+				r\n\
+				.\n\
+				entry @ down\n\
+				obj q player\n\
+				obj r rock\n"
+			));
+			let mut sim = Simulation::new(&column_level);
+			sim.player_move(DOWN);
+			assert_eq!(rows(&sim.grid), [".", "@", "@", "r"], "with {tick_order}");
+		}
+	}
+
+	#[test]
+	fn two_players_to_one_tile() {
+		// Both players go through a teleporter and would come out of the same one,
+		// none of them moves.
+		let level = level(
+			"id same_tile\n\
+			size 4 3\n\
+			grid\n\
+			. t . .\n\
+			@ t . .\n\
+			q t . .\n\
+			entry @ right\n\
+			obj q player\n\
+			obj t teleporter:0\n",
+		);
+		let mut sim = Simulation::new(&level);
+		sim.player_move(RIGHT);
+		assert_eq!(rows(&sim.grid), [". t . .", "@ t . .", "@ t . ."]);
+	}
+
+	#[test]
+	fn rock_pushed_into_player() {
+		// The player in front of the rock moves along, unless it is blocked.
+		let cases = [("@ r q .", ". @ r @"), ("@ r q #", "@ r @ #")];
+		for (row, expected_row) in cases {
+			let level = level(&format!(
+				"id push_into_player\n\
+				size 4 1\n\
+				grid\n\
+				{row}\n\
+				entry @ right\n\
+				obj q player\n\
+				obj r rock\n\
+				obj # wall\n"
+			));
+			let mut sim = Simulation::new(&level);
+			sim.player_move(RIGHT);
+			assert_eq!(rows(&sim.grid), [expected_row], "pushing in {row}");
+		}
+	}
+
+	#[test]
+	fn bunny_sees_through_wall_with_holes() {
+		// The player bumps into the edge, the bunny flees if it sees the player.
+		let cases = [
+			("@ . h . n . .", "@ . h . . n ."),
+			("@ . # . n . .", "@ . # . n . ."),
+		];
+		for (row, expected_row) in cases {
+			let level = level(&format!(
+				"id bunny\n\
+				size 7 1\n\
+				grid\n\
+				{row}\n\
+				entry @ right\n\
+				obj h wall_with_holes\n\
+				obj # wall\n\
+				obj n bunny\n"
+			));
+			let mut sim = Simulation::new(&level);
+			sim.player_move(LEFT);
+			assert_eq!(rows(&sim.grid), [expected_row], "spooking in {row}");
+		}
+	}
+
+	#[test]
+	fn two_bunnies_fleeing_into_one_corner() {
+		// Each bunny sees a player and flees into the corner, with two bunnies both stay.
+		let cases = [
+			(
+				[
+					". n . . @",
+					"n . . . .",
+					". . . . .",
+					". . . . .",
+					"q . . . .",
+				],
+				[
+					". n . . @",
+					"n . . . .",
+					". . . . .",
+					"@ . . . .",
+					". . . . .",
+				],
+			),
+			(
+				[
+					". n . . @",
+					". . . . .",
+					". . . . .",
+					". . . . .",
+					"q . . . .",
+				],
+				[
+					"n . . . @",
+					". . . . .",
+					". . . . .",
+					"@ . . . .",
+					". . . . .",
+				],
+			),
+		];
+		for (grid_rows, expected_rows) in cases {
+			let level = level(&format!(
+				"id bunnies\n\
+				size 5 5\n\
+				grid\n\
+				{}\n\
+				entry @ left\n\
+				obj q player\n\
+				obj n bunny\n",
+				grid_rows.join("\n")
+			));
+			let mut sim = Simulation::new(&level);
+			sim.player_move(UP);
+			assert_eq!(rows(&sim.grid), expected_rows);
+		}
+	}
+
+	#[test]
+	fn size_after_coords() {
+		for coords_directive in [
+			"entry_at 10 10 right",
+			"entry_at 10 10 right back",
+			"tag 10 10 thing",
+			"note 10 10 20 front Hello",
+		] {
+			let text = format!("id late_size\n{coords_directive}\nsize 5 5\n");
+			let level = Level::load_from_text(&text, "late_size");
+			assert!(
+				level
+					.error_messages
+					.iter()
+					.any(|message| message.contains("size must be given before the coords at line 2")),
+				"{:?}",
+				level.error_messages
+			);
+			// The coords are still in the grid, the level can be entered.
+			let sim = Simulation::new(&level);
+			assert_eq!(sim.grid.w, Grid::DEFAULT_W);
+		}
+	}
+
+	#[test]
+	fn move_outcome() {
+		let level = level(
+			"id outcome\n\
+			size 4 2\n\
+			grid\n\
+			@ r . #\n\
+			. c . .\n\
+			entry @ right\n\
+			obj r rock\n\
+			obj c cheese\n\
+			obj # wall\n",
+		);
+		let mut sim = Simulation::new(&level);
+		let moved = |outcome: &MoveOutcome| -> Vec<_> {
+			outcome
+				.moved
+				.iter()
+				.map(|(kind, src, dst)| (kind.descr().unwrap(), (src.x, src.y), (dst.x, dst.y)))
+				.collect()
+		};
+		let outcome = sim.player_move(RIGHT);
+		assert_eq!(
+			moved(&outcome),
+			[
+				("player".to_string(), (0, 0), (1, 0)),
+				("rock".to_string(), (1, 0), (2, 0))
+			]
+		);
+		assert!(!outcome.some_move_failed);
+		assert_eq!(outcome.cheese_gained, 0);
+		// The rock is pushed into the wall.
+		let outcome = sim.player_move(RIGHT);
+		assert_eq!(moved(&outcome), []);
+		assert!(outcome.some_move_failed);
+		assert!(!outcome.board_changed);
+		let outcome = sim.player_move(DOWN);
+		assert_eq!(moved(&outcome), [("player".to_string(), (1, 0), (1, 1))]);
+		assert_eq!(outcome.cheese_gained, 1);
+	}
+
+	#[test]
+	fn note_text_starting_with_an_option_keyword() {
+		let level = level(
+			"id notes\n\
+			note 0 0 20 front box of rocks\n\
+			note 0 1 20 front page 3 is missing\n\
+			note 0 2 20 front page 3 box | box of rocks\n",
+		);
+		let note = &level.notes[0];
+		assert_eq!(note.text, "box of rocks");
+		assert!(!note.has_box);
+		let note = &level.notes[1];
+		assert_eq!(note.text, "page 3 is missing");
+		assert_eq!(note.page, None);
+		let note = &level.notes[2];
+		assert_eq!(note.text, "box of rocks");
+		assert!(note.has_box);
+		assert_eq!(note.page, Some(3));
+		let error_messages =
+			Level::load_from_text("id notes\nnote 0 0 20 front bold | text", "test_level")
+				.error_messages;
+		assert_eq!(error_messages.len(), 1);
+		assert!(error_messages[0].contains("unknown note option \"bold\""));
+	}
+
+	#[test]
+	fn fnv_hasher_known_values() {
+		let hash = |bytes: &[u8]| {
+			let mut hasher = FnvHasher::default();
+			hasher.write(bytes);
+			hasher.finish()
+		};
+		assert_eq!(hash(b""), 0xcbf29ce484222325);
+		assert_eq!(hash(b"a"), 0xaf63dc4c8601ec8c);
+		assert_eq!(hash(b"foobar"), 0x85944171f73967e8);
+	}
+
+	#[test]
+	fn magnet_does_not_see_around_a_wrapping_grid() {
+		let level = level(
+			"id magnet\n\
+			size 4 2\n\
+			wrap\n\
+			grid\n\
+			r # M .\n\
+			@ . . .\n\
+			entry @ right\n\
+			obj r rock\n\
+			obj # wall\n\
+			obj M magnet\n",
+		);
+		let mut sim = Simulation::new(&level);
+		sim.player_move(RIGHT);
+		assert_eq!(rows(&sim.grid), ["r # ? .", ". @ . ."]);
+	}
+
+	#[test]
+	fn only_the_first_id_counts() {
+		let level = Level::load_from_text("id first\nid second\n", "test_level");
+		assert_eq!(level.id, "first");
+		assert_eq!(
+			level.error_messages,
+			["structural error: id already given at line 1, at line 2"]
+		);
+	}
+}